@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+
+use crate::{consts::AdmonitionsConfig, utils::escape_attribute};
+
+/// Splits a `[!NOTE] Title` marker into its kind and the rest of the text.
+pub fn parse_marker(text: &str) -> Option<(&str, &str)> {
+    let (kind, rest) = text.strip_prefix("[!")?.split_once(']')?;
+    (!kind.is_empty() && kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        .then_some((kind, rest))
+}
+
+pub fn admonition_open(kind: &str, title: &str) -> String {
+    let kind = escape_attribute(&kind.to_lowercase());
+    format!(
+        "<aside class=\"admonition admonition-{}\">\n<p class=\"admonition-title\">{}</p>\n",
+        kind,
+        escape_attribute(title)
+    )
+}
+
+/// The run of backticks or tildes opening a code fence.
+fn fence_marker(line: &str) -> Option<&str> {
+    let ch = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let marker = &line[..line.find(|c| c != ch).unwrap_or(line.len())];
+    (marker.len() >= 3).then_some(marker)
+}
+
+/// Rewrites `:::kind Title` … `:::` containers into `> [!KIND] Title`
+/// blockquotes, which are then rendered like GitHub alerts. Lines are
+/// rewritten in place so errors still point at the right line. Containers
+/// can nest, and `:::` lines in code fences are left alone.
+pub fn expand_containers<'a>(input: &'a str, config: &AdmonitionsConfig) -> Cow<'a, str> {
+    if !input.contains(":::") {
+        return Cow::Borrowed(input);
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut depth = 0;
+    let mut fence: Option<&str> = None;
+
+    for line in input.split_inclusive('\n') {
+        let prefix = "> ".repeat(depth);
+        let trimmed = line.trim();
+
+        if let Some(marker) = fence {
+            let ch = marker.chars().next().unwrap_or('`');
+            if trimmed.len() >= marker.len() && trimmed.chars().all(|c| c == ch) {
+                fence = None;
+            }
+        } else if let Some(marker) = fence_marker(trimmed) {
+            fence = Some(marker);
+        } else if depth > 0 && trimmed == ":::" {
+            depth -= 1;
+            output.push_str(&"> ".repeat(depth));
+            output.push('\n');
+            continue;
+        } else if let Some(container) = trimmed.strip_prefix(":::") {
+            let (kind, title) = container
+                .trim_start()
+                .split_once(char::is_whitespace)
+                .unwrap_or((container.trim_start(), ""));
+            if config.title(kind).is_some() {
+                output.push_str(&format!(
+                    "{}> [!{}] {}\n",
+                    prefix,
+                    kind.to_uppercase(),
+                    title.trim()
+                ));
+                depth += 1;
+                continue;
+            }
+        }
+
+        output.push_str(&prefix);
+        output.push_str(line);
+    }
+    Cow::Owned(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_containers_rewrites_nested_containers() {
+        let input = ":::note Read this\nouter\n:::tip\ninner\n:::\n:::\nafter\n";
+        assert_eq!(
+            expand_containers(input, &AdmonitionsConfig::default()),
+            "> [!NOTE] Read this\n> outer\n> > [!TIP] \n> > inner\n> \n\nafter\n"
+        );
+    }
+
+    #[test]
+    fn expand_containers_leaves_code_and_unknown_kinds() {
+        let input = "```\n:::note\n```\n:::unknown\n:::\n";
+        assert_eq!(
+            expand_containers(input, &AdmonitionsConfig::default()),
+            input
+        );
+    }
+
+    #[test]
+    fn parse_marker_splits_kind_and_title() {
+        assert_eq!(parse_marker("[!NOTE] Title"), Some(("NOTE", " Title")));
+        assert_eq!(parse_marker("[!] x"), None);
+        assert_eq!(parse_marker("[!a b] x"), None);
+    }
+}
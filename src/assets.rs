@@ -0,0 +1,710 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use globset::Glob;
+use serde::Serialize;
+use sha2::{Digest, Sha384};
+use tera::{Function, Value};
+use tokio::fs::{read_dir, read_to_string, remove_file};
+use walkdir::WalkDir;
+
+/// Project level directory for scss files that aren't part of `static/`.
+pub const SASS_DIR: &str = "sass";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BundleKind {
+    Css,
+    Js,
+}
+
+impl BundleKind {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+            Some("css") => Ok(Self::Css),
+            Some("js") => Ok(Self::Js),
+            _ => bail!("Bundle '{}' must end in .css or .js", name),
+        }
+    }
+
+    fn separator(&self) -> &'static str {
+        match self {
+            Self::Css => "\n",
+            // Guards against files that rely on automatic semicolon insertion
+            Self::Js => ";\n",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AssetEntry {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<String>,
+}
+
+/// Maps asset paths, relative to the output root, to the url they are
+/// served from and, for fingerprinted assets, their Subresource Integrity
+/// hash. Assets that aren't in the manifest are served as is.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(transparent)]
+pub struct AssetManifest {
+    entries: BTreeMap<String, AssetEntry>,
+}
+
+impl AssetManifest {
+    pub fn url(&self, path: &str) -> String {
+        let path = normalize_asset_path(path);
+        self.entries
+            .get(&path)
+            .map(|entry| entry.url.clone())
+            .unwrap_or_else(|| format!("/{}", path))
+    }
+
+    pub fn integrity(&self, path: &str) -> Option<&str> {
+        self.entries
+            .get(&normalize_asset_path(path))
+            .and_then(|entry| entry.integrity.as_deref())
+    }
+
+    /// Records an asset and returns the path, relative to the output root,
+    /// it has to be written to. Fingerprinted assets get a hash of their
+    /// content in the file name, e.g. `style.3f9a1c0b7e2d4a65.css`. Only
+    /// they get an integrity hash, as the content behind any other url can
+    /// change, like when `serve` recompiles a stylesheet.
+    pub fn insert(&mut self, path: &str, content: &[u8], fingerprint: bool) -> String {
+        let path = normalize_asset_path(path);
+        let digest = Sha384::digest(content);
+
+        let (output_path, integrity) = if fingerprint {
+            (
+                fingerprinted_path(&path, &digest),
+                Some(format!("sha384-{}", STANDARD.encode(digest))),
+            )
+        } else {
+            (path.clone(), None)
+        };
+        self.entries.insert(
+            path,
+            AssetEntry {
+                url: format!("/{}", output_path),
+                integrity,
+            },
+        );
+        output_path
+    }
+}
+
+fn normalize_asset_path(path: &str) -> String {
+    path.trim_start_matches('/').replace('\\', "/")
+}
+
+/// Length of the hex encoded hash in fingerprinted file names.
+const FINGERPRINT_LEN: usize = 16;
+
+fn fingerprinted_path(path: &str, digest: &[u8]) -> String {
+    let hash = digest[..FINGERPRINT_LEN / 2]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let (dir, file) = match path.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), path),
+    };
+    match file.rsplit_once('.') {
+        Some((stem, ext)) => format!("{}{}.{}.{}", dir, stem, hash, ext),
+        None => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+/// Whether `name` is a fingerprinted version of the asset file `file`,
+/// e.g. `style.3f9a1c0b7e2d4a65.css` of `style.css`.
+fn is_fingerprint_of(name: &str, file: &str) -> bool {
+    let (stem, ext) = match file.rsplit_once('.') {
+        Some((stem, ext)) => (stem, Some(ext)),
+        None => (file, None),
+    };
+    let Some(rest) = name
+        .strip_prefix(stem)
+        .and_then(|rest| rest.strip_prefix('.'))
+    else {
+        return false;
+    };
+    let hash = match ext {
+        Some(ext) => rest
+            .strip_suffix(ext)
+            .and_then(|rest| rest.strip_suffix('.')),
+        None => Some(rest),
+    };
+    hash.is_some_and(|hash| {
+        hash.len() == FINGERPRINT_LEN && hash.bytes().all(|b| b.is_ascii_hexdigit())
+    })
+}
+
+/// Removes the files earlier builds wrote for an asset under another
+/// fingerprint, or without one, so they don't pile up in the output dir.
+/// `output_path` is the file the asset is written to now.
+pub async fn remove_stale_fingerprints(
+    output_dir: &Path,
+    path: &str,
+    output_path: &Path,
+) -> Result<()> {
+    let path = normalize_asset_path(path);
+    let plain_path = output_dir.join(&path);
+    let (Some(dir), Some(file)) = (
+        plain_path.parent(),
+        plain_path.file_name().and_then(|name| name.to_str()),
+    ) else {
+        return Ok(());
+    };
+    let Ok(mut entries) = read_dir(dir).await else {
+        return Ok(());
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        let stale = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if stale != output_path && (name == file || is_fingerprint_of(&name, file)) {
+            remove_file(&stale)
+                .await
+                .with_context(|| format!("Failed to remove {}", stale.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// A css or js file waiting to be recorded in the manifest and written.
+#[derive(Debug)]
+pub struct PendingAsset {
+    pub path: String,
+    pub content: String,
+    pub source: PathBuf,
+}
+
+/// Records assets in the manifest, returning each with the path it has to
+/// be written to. Stylesheets are rewritten to point at the urls of the
+/// assets they reference, so those are recorded first, whatever order the
+/// assets come in. Stylesheets that import each other in a cycle are
+/// recorded in the order given.
+pub fn record_assets(
+    assets: Vec<PendingAsset>,
+    manifest: &mut AssetManifest,
+    fingerprint: bool,
+) -> Vec<(String, PendingAsset)> {
+    let mut pending = assets
+        .into_iter()
+        .map(|mut asset| {
+            asset.path = normalize_asset_path(&asset.path);
+            asset
+        })
+        .collect::<Vec<PendingAsset>>();
+    let mut outputs = vec![None; pending.len()];
+
+    while outputs.iter().any(Option::is_none) {
+        let waiting = |path: &str| {
+            pending
+                .iter()
+                .zip(&outputs)
+                .any(|(asset, output)| output.is_none() && asset.path == path)
+        };
+        let mut ready = (0..pending.len())
+            .filter(|&i| outputs[i].is_none())
+            .filter(|&i| {
+                let asset = &pending[i];
+                !asset.path.ends_with(".css")
+                    || css_references(&asset.content, &asset.path)
+                        .iter()
+                        .all(|path| *path == asset.path || !waiting(path))
+            })
+            .collect::<Vec<usize>>();
+        // Nothing is ready only when stylesheets import each other in a cycle
+        if ready.is_empty() {
+            ready.extend(outputs.iter().position(Option::is_none));
+        }
+
+        for i in ready {
+            let asset = &mut pending[i];
+            if asset.path.ends_with(".css") {
+                asset.content = rewrite_css_urls(&asset.content, &asset.path, manifest);
+            }
+            outputs[i] = Some(manifest.insert(&asset.path, asset.content.as_bytes(), fingerprint));
+        }
+    }
+
+    outputs.into_iter().flatten().zip(pending).collect()
+}
+
+/// Points the `url()` references and `@import` strings of a stylesheet at
+/// the urls of assets that are already in the manifest, so imports keep
+/// working once the files they name are fingerprinted.
+pub fn rewrite_css_urls(css: &str, css_path: &str, manifest: &AssetManifest) -> String {
+    map_css_references(css, css_path, |path| {
+        manifest.entries.get(path).map(|entry| entry.url.clone())
+    })
+}
+
+/// The paths, relative to the output root, a stylesheet refers to.
+fn css_references(css: &str, css_path: &str) -> Vec<String> {
+    let mut references = Vec::new();
+    map_css_references(css, css_path, |path| {
+        references.push(path.to_string());
+        None
+    });
+    references
+}
+
+/// Replaces the `url()` references and `@import` strings of a stylesheet
+/// with the url `replace` returns for the path they resolve to, leaving
+/// them alone when it returns `None`.
+fn map_css_references(
+    css: &str,
+    css_path: &str,
+    mut replace: impl FnMut(&str) -> Option<String>,
+) -> String {
+    let css_path = normalize_asset_path(css_path);
+    let base = css_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let css = rewrite_url_functions(css, base, &mut replace);
+    rewrite_import_strings(&css, base, &mut replace)
+}
+
+fn rewrite_url_functions(
+    css: &str,
+    base: &str,
+    replace: &mut impl FnMut(&str) -> Option<String>,
+) -> String {
+    let mut rewritten = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("url(") {
+        let (before, after) = rest.split_at(start + "url(".len());
+        rewritten.push_str(before);
+
+        let Some(end) = after.find(')') else {
+            rest = after;
+            break;
+        };
+        let reference = &after[..end];
+        let target = reference.trim().trim_matches(['"', '\'']);
+        match resolve_css_reference(base, target).and_then(|path| replace(&path)) {
+            Some(url) => rewritten.push_str(&format!("\"{}\"", url)),
+            None => rewritten.push_str(reference),
+        }
+        rest = &after[end..];
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// Rewrites the bare string form of `@import`, e.g. `@import "reset.css";`.
+fn rewrite_import_strings(
+    css: &str,
+    base: &str,
+    replace: &mut impl FnMut(&str) -> Option<String>,
+) -> String {
+    let mut rewritten = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("@import") {
+        let (before, after) = rest.split_at(start + "@import".len());
+        rewritten.push_str(before);
+
+        let trimmed = after.trim_start();
+        rewritten.push_str(&after[..after.len() - trimmed.len()]);
+        rest = trimmed;
+        let Some(quote) = trimmed.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let Some(end) = trimmed[1..].find(quote) else {
+            break;
+        };
+        let target = &trimmed[1..end + 1];
+        match resolve_css_reference(base, target).and_then(|path| replace(&path)) {
+            Some(url) => rewritten.push_str(&format!("\"{}\"", url)),
+            None => rewritten.push_str(&trimmed[..end + 2]),
+        }
+        rest = &trimmed[end + 2..];
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// Resolves a `url()` reference against the directory of the stylesheet,
+/// ignoring external urls, data urls and references with a query.
+fn resolve_css_reference(base: &str, target: &str) -> Option<String> {
+    if target.is_empty() || target.starts_with("//") || target.contains([':', '?', '#']) {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    let target = match target.strip_prefix('/') {
+        Some(absolute) => absolute,
+        None => {
+            segments.extend(base.split('/').filter(|segment| !segment.is_empty()));
+            target
+        }
+    };
+    for segment in target.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+/// Tera `asset_url` function, e.g. `{{ asset_url(path="bundle.css") }}`.
+pub struct AssetUrl {
+    manifest: Arc<AssetManifest>,
+}
+
+impl AssetUrl {
+    pub fn new(manifest: Arc<AssetManifest>) -> Self {
+        Self { manifest }
+    }
+}
+
+impl Function for AssetUrl {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let path = asset_path_arg("asset_url", args)?;
+        Ok(Value::String(self.manifest.url(path)))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// Tera `asset_integrity` function returning the SRI hash of a fingerprinted
+/// css or js asset, or an empty string for any other asset, e.g.
+/// `{% set integrity = asset_integrity(path="style.css") %}`.
+pub struct AssetIntegrity {
+    manifest: Arc<AssetManifest>,
+}
+
+impl AssetIntegrity {
+    pub fn new(manifest: Arc<AssetManifest>) -> Self {
+        Self { manifest }
+    }
+}
+
+impl Function for AssetIntegrity {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let path = asset_path_arg("asset_integrity", args)?;
+        let integrity = self.manifest.integrity(path).unwrap_or_default();
+        Ok(Value::String(integrity.to_string()))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+fn asset_path_arg<'a>(function: &str, args: &'a HashMap<String, Value>) -> tera::Result<&'a str> {
+    args.get("path").and_then(Value::as_str).ok_or_else(|| {
+        tera::Error::msg(format!("Function `{}` expects a `path` argument", function))
+    })
+}
+
+/// Expands the glob patterns of a bundle against the static directory. Files
+/// keep the order of the patterns that first matched them, and files
+/// matched by the same pattern are sorted by path.
+fn expand_bundle_inputs(static_dir: &Path, patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let files = WalkDir::new(static_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .collect::<Vec<PathBuf>>();
+
+    let mut inputs: Vec<PathBuf> = Vec::new();
+    for pattern in patterns {
+        let matcher = Glob::new(pattern)
+            .with_context(|| format!("Invalid bundle pattern: {}", pattern))?
+            .compile_matcher();
+
+        let matches = files
+            .iter()
+            .filter(|file| {
+                file.strip_prefix(static_dir)
+                    .is_ok_and(|rel_path| matcher.is_match(rel_path))
+            })
+            .collect::<Vec<&PathBuf>>();
+
+        if matches.is_empty() {
+            bail!("Bundle pattern '{}' matched no files", pattern);
+        }
+
+        for file in matches {
+            if !inputs.contains(file) {
+                inputs.push(file.clone());
+            }
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// Concatenates the files of a bundle, minifying the result when asked to.
+pub async fn create_bundle(
+    static_dir: &Path,
+    name: &str,
+    patterns: &[String],
+    minify: bool,
+) -> Result<String> {
+    let kind = BundleKind::from_name(name)?;
+
+    let mut sources = Vec::new();
+    for input in expand_bundle_inputs(static_dir, patterns)? {
+        sources.push(
+            read_to_string(&input)
+                .await
+                .with_context(|| format!("Failed to read bundle input: {}", input.display()))?,
+        );
+    }
+    let bundle = sources.join(kind.separator());
+
+    if minify {
+        minify_asset(kind, &bundle).with_context(|| format!("Failed to minify bundle: {}", name))
+    } else {
+        Ok(bundle)
+    }
+}
+
+fn minify_asset(kind: BundleKind, source: &str) -> Result<String> {
+    let minified = match kind {
+        BundleKind::Css => minifier::css::minify(source)
+            .map_err(|e| anyhow!(e))?
+            .to_string(),
+        BundleKind::Js => minifier::js::minify(source)
+            .map_err(|e| anyhow!(e))?
+            .to_string(),
+    };
+    Ok(minified)
+}
+
+#[derive(Debug)]
+pub struct Stylesheet {
+    pub source: PathBuf,
+    /// Path of the compiled css, relative to the output dir.
+    pub path: String,
+    pub css: String,
+}
+
+pub fn is_scss(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("scss")
+}
+
+fn is_partial(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('_'))
+}
+
+fn scss_files(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && is_scss(e.path()))
+        .map(|e| e.into_path())
+}
+
+/// Compiles every scss file in `static/` and `sass/` to css, keeping its
+/// path relative to the directory it came from. Partials, whose names start
+/// with `_`, are only compiled through the files that import them.
+pub fn compile_stylesheets(static_dir: &Path, minify: bool) -> Result<Vec<Stylesheet>> {
+    let sass_dir = Path::new(SASS_DIR);
+    let style = if minify {
+        grass::OutputStyle::Compressed
+    } else {
+        grass::OutputStyle::Expanded
+    };
+    let options = grass::Options::default()
+        .load_paths(&[static_dir, sass_dir])
+        .style(style);
+
+    let mut stylesheets = Vec::new();
+    for dir in [static_dir, sass_dir] {
+        for source in scss_files(dir).filter(|path| !is_partial(path)) {
+            // grass errors already point at the offending line of the scss
+            let css = grass::from_path(&source, &options)
+                .map_err(|e| anyhow!("Failed to compile {}:\n{}", source.display(), e))?;
+
+            let path = source
+                .strip_prefix(dir)?
+                .with_extension("css")
+                .to_string_lossy()
+                .replace('\\', "/");
+            stylesheets.push(Stylesheet { source, path, css });
+        }
+    }
+
+    Ok(stylesheets)
+}
+
+/// Number of scss files and the latest time one of them changed, partials
+/// included. `serve` polls this to notice when to recompile stylesheets.
+pub fn stylesheets_state(static_dir: &Path) -> (usize, Option<SystemTime>) {
+    let mut count = 0;
+    let mut latest = None;
+    for path in scss_files(static_dir).chain(scss_files(Path::new(SASS_DIR))) {
+        count += 1;
+        let modified = path.metadata().and_then(|m| m.modified()).ok();
+        latest = latest.max(modified);
+    }
+    (count, latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest_with(paths: &[&str]) -> AssetManifest {
+        let mut manifest = AssetManifest::default();
+        for path in paths {
+            manifest.insert(path, path.as_bytes(), true);
+        }
+        manifest
+    }
+
+    #[test]
+    fn fingerprinted_path_keeps_the_directory_and_extension() {
+        let digest = [0xab; 48];
+        assert_eq!(
+            fingerprinted_path("css/style.css", &digest),
+            "css/style.abababababababab.css"
+        );
+        assert_eq!(
+            fingerprinted_path("LICENSE", &digest),
+            "LICENSE.abababababababab"
+        );
+    }
+
+    #[test]
+    fn is_fingerprint_of_matches_only_hashed_names() {
+        assert!(is_fingerprint_of("style.3f9a1c0b7e2d4a65.css", "style.css"));
+        assert!(!is_fingerprint_of("style.css", "style.css"));
+        assert!(!is_fingerprint_of("style.min.css", "style.css"));
+        assert!(!is_fingerprint_of(
+            "style.3f9a1c0b7e2d4a65.css.gz",
+            "style.css"
+        ));
+        assert!(!is_fingerprint_of(
+            "other.3f9a1c0b7e2d4a65.css",
+            "style.css"
+        ));
+    }
+
+    #[test]
+    fn only_fingerprinted_assets_get_an_integrity_hash() {
+        let mut manifest = AssetManifest::default();
+        assert_eq!(manifest.insert("/style.css", b"body{}", false), "style.css");
+        assert_eq!(manifest.url("style.css"), "/style.css");
+        assert_eq!(manifest.integrity("style.css"), None);
+
+        let path = manifest.insert("style.css", b"body{}", true);
+        assert_eq!(manifest.url("style.css"), format!("/{}", path));
+        assert!(
+            manifest
+                .integrity("style.css")
+                .unwrap()
+                .starts_with("sha384-")
+        );
+    }
+
+    #[test]
+    fn rewrite_css_urls_points_references_at_fingerprinted_files() {
+        let manifest = manifest_with(&["fonts/a.woff2", "css/reset.css", "theme.css"]);
+        let font = manifest.url("fonts/a.woff2");
+        let reset = manifest.url("css/reset.css");
+        let theme = manifest.url("theme.css");
+
+        let css = "@import \"reset.css\";\n@import '../theme.css' screen;\n\
+                   @import url(missing.css);\n\
+                   @font-face { src: url('../fonts/a.woff2'), url(data:font/woff2;base64,AA); }";
+        let rewritten = rewrite_css_urls(css, "css/main.css", &manifest);
+
+        assert!(rewritten.contains(&format!("@import \"{}\";", reset)));
+        assert!(rewritten.contains(&format!("@import \"{}\" screen;", theme)));
+        assert!(rewritten.contains("@import url(missing.css);"));
+        assert!(rewritten.contains(&format!("url(\"{}\")", font)));
+        assert!(rewritten.contains("url(data:font/woff2;base64,AA)"));
+    }
+
+    fn pending(path: &str, content: &str) -> PendingAsset {
+        PendingAsset {
+            path: path.to_string(),
+            content: content.to_string(),
+            source: PathBuf::from(path),
+        }
+    }
+
+    #[test]
+    fn record_assets_rewrites_forward_references() {
+        let mut manifest = AssetManifest::default();
+        let assets = record_assets(
+            vec![
+                pending("css/a.css", "@import \"b.css\";"),
+                pending("css/b.css", "body { background: url(../img.js); }"),
+                pending("img.js", "let a = 1;"),
+            ],
+            &mut manifest,
+            true,
+        );
+
+        let paths = assets
+            .iter()
+            .map(|(_, asset)| asset.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["css/a.css", "css/b.css", "img.js"]);
+        assert!(assets[0].1.content.contains(&manifest.url("css/b.css")));
+        assert!(assets[1].1.content.contains(&manifest.url("img.js")));
+        for (output_path, asset) in &assets {
+            assert_eq!(format!("/{}", output_path), manifest.url(&asset.path));
+            assert_eq!(
+                *output_path,
+                fingerprinted_path(&asset.path, &Sha384::digest(&asset.content))
+            );
+        }
+    }
+
+    #[test]
+    fn record_assets_breaks_import_cycles_in_order() {
+        let mut manifest = AssetManifest::default();
+        let assets = record_assets(
+            vec![
+                pending("a.css", "@import \"b.css\";"),
+                pending("b.css", "@import \"a.css\";"),
+            ],
+            &mut manifest,
+            true,
+        );
+
+        assert_eq!(assets[0].1.content, "@import \"b.css\";");
+        assert!(assets[1].1.content.contains(&manifest.url("a.css")));
+    }
+
+    #[test]
+    fn resolve_css_reference_ignores_external_urls() {
+        assert_eq!(
+            resolve_css_reference("css", "../img/a.png"),
+            Some("img/a.png".to_string())
+        );
+        assert_eq!(
+            resolve_css_reference("css", "/img/a.png"),
+            Some("img/a.png".to_string())
+        );
+        assert_eq!(resolve_css_reference("css", "https://x.org/a.png"), None);
+        assert_eq!(resolve_css_reference("css", "a.png?v=2"), None);
+        assert_eq!(resolve_css_reference("", "../a.png"), None);
+    }
+}
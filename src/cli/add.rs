@@ -54,8 +54,6 @@ pub async fn add_content<P: AsRef<Path>>(dirname: P) -> Result<()> {
 
 fn get_timestamp() -> Result<String> {
     let now = SystemTime::now();
-    let timestamp = print_system_time_to_rfc3339(&now)
-        .replace(':', "-")
-        .replace('.', "-");
+    let timestamp = print_system_time_to_rfc3339(&now).replace([':', '.'], "-");
     Ok(timestamp)
 }
@@ -1,57 +1,259 @@
 use crate::{
-    consts::FrontMatter,
-    utils::{copy_dir, get_config, get_content_dir, get_slug},
+    admonition::{admonition_open, expand_containers, parse_marker},
+    assets::{
+        AssetIntegrity, AssetManifest, AssetUrl, PendingAsset, compile_stylesheets, create_bundle,
+        is_scss, record_assets, remove_stale_fingerprints,
+    },
+    compress::precompress,
+    consts::{
+        AdmonitionsConfig, BUNDLE_INDEX, BuildProfile, Config, FrontMatter, MarkdownConfig,
+        TrustLevel,
+    },
+    date::{DateFilter, parse_date, parse_timezone},
+    diagram::{diagram_id, is_diagram, render_diagram},
+    highlight::{
+        CodeAttributes, DiffLine, find_syntax, split_diff, split_highlighted_lines, syntax_set,
+        syntax_stylesheets, warn_unknown_language,
+    },
+    images::{ImageSet, is_image, process_image},
+    math::math_to_mathml,
+    permalink::{
+        permalink_output_path, permalink_pattern, resolve_permalink, resolve_relative_url,
+    },
+    redirect::{Redirect, nginx_map_file, normalize_alias, redirect_stub, redirects_file},
+    sanitize::{report_stripped, sanitize_html},
+    snippet::include_snippet,
+    utils::{
+        copy_if_changed, escape_attribute, get_bundle_dir, get_config, get_content_dir,
+        normalize_path, resolve_slug, write_if_changed,
+    },
 };
 use anyhow::{Context, Result, bail};
-use comrak::{adapters::SyntaxHighlighterAdapter, markdown_to_html_with_plugins, Options, Plugins};
+use chrono::{DateTime, FixedOffset, Utc};
+use comrak::{
+    Anchorizer, Arena, Options, Plugins,
+    adapters::SyntaxHighlighterAdapter,
+    format_html_with_plugins,
+    html::collect_text,
+    nodes::{Ast, AstNode, LineColumn, NodeValue},
+    parse_document,
+};
+use globset::{Glob, GlobSetBuilder};
 use gray_matter::{Matter, engine::YAML};
 use serde::Serialize;
-use std::{collections::HashMap, path::Path};
-use tera::Tera;
-use tokio::fs::{copy, create_dir_all, read_to_string, write};
-use walkdir::WalkDir;
-use syntect::{highlighting::ThemeSet, html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator}};
+use slug::slugify;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use syntect::html::{ClassStyle, ClassedHTMLGenerator};
 use syntect::parsing::SyntaxSet;
 use syntect::util::LinesWithEndings;
+use tera::Tera;
+use tokio::fs::{create_dir_all, read_to_string, write};
+use walkdir::WalkDir;
 
+const SUMMARY_MARKER: &str = "<!-- more -->";
+
+/// Variables `render_page` sets itself. Front matter `extra` values are also
+/// exposed as variables of their own, except under these names, so a page
+/// can't replace the navbar or its sanitised content.
+const RESERVED_TEMPLATE_VARIABLES: [&str; 16] = [
+    "author",
+    "content",
+    "date",
+    "description",
+    "extra",
+    "heading",
+    "navbar",
+    "raw_content",
+    "reading_time",
+    "summary",
+    "tags",
+    "title",
+    "toc",
+    "updated",
+    "url",
+    "word_count",
+];
+
+/// A content file with its front matter read, which is all that deciding
+/// whether it is published and where it goes needs.
 #[derive(Debug)]
-struct Document {
+struct Page {
     metadata: FrontMatter,
+    slug: String,
     content: String,
+    /// See `RenderContext::line_offset`.
+    line_offset: usize,
+    date: Option<DateTime<FixedOffset>>,
+    updated: Option<DateTime<FixedOffset>>,
+    expires: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Debug)]
+struct Document {
+    page: Page,
     html_content: String,
+    toc: Vec<TocEntry>,
+    summary: String,
+    word_count: usize,
+    reading_time: usize,
+}
+
+struct RenderedMarkdown {
+    html: String,
+    /// Html of everything before a `<!-- more -->` marker, if there is one.
+    summary: Option<String>,
+    headings: Vec<TocEntry>,
+    words: Vec<String>,
+    /// Links to content files that aren't built, with their line.
+    unresolved_links: Vec<(usize, String)>,
+    unknown_languages: Vec<String>,
+    /// Info string words that aren't code block attributes, with their line.
+    unknown_attributes: Vec<(usize, String)>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct TocEntry {
+    level: u8,
+    id: String,
+    title: String,
+    children: Vec<TocEntry>,
 }
 
 #[derive(Serialize, Debug, Clone)]
 struct PostInfo {
     slug: String,
     title: String,
-    date: Option<String>,
+    date: Option<DateTime<FixedOffset>>,
+    updated: Option<DateTime<FixedOffset>>,
     description: Option<String>,
     tags: Option<Vec<String>>,
     url: String,
+    summary: String,
+    word_count: usize,
+    reading_time: usize,
 }
 
 #[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
 enum NavType {
-    FILE,
-    DIR,
+    File,
+    Dir,
 }
 
 #[derive(Debug, Serialize, PartialEq)]
 struct NavItem {
     name: String,
     nav_type: NavType,
+    url: String,
+}
+
+/// State shared by every step of a build.
+#[derive(Default)]
+struct BuildState {
+    profile: BuildProfile,
+    fingerprint: bool,
+    minify_html: bool,
+    outputs: HashMap<PathBuf, PathBuf>,
+    redirects: Vec<Redirect>,
+    assets: Arc<AssetManifest>,
+    /// Css and js files waiting for `write_assets`.
+    pending_assets: Vec<PendingAsset>,
+    images: ImageSet,
+    /// Url of every page that is built, keyed by the path of its file.
+    pages: HashMap<PathBuf, String>,
+}
+
+impl BuildState {
+    /// Records which source file produced each output path, so that two
+    /// pages or aliases resolving to the same file are reported instead of
+    /// silently overwriting each other.
+    fn claim_output_path(&mut self, output_path: &Path, source: &Path) -> Result<()> {
+        if let Some(existing) = self
+            .outputs
+            .insert(output_path.to_path_buf(), source.to_path_buf())
+        {
+            bail!(
+                "Duplicate output path {}: both {} and {} resolve to it, change the slug or alias of one of them",
+                output_path.display(),
+                existing.display(),
+                source.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Queues a css or js file to be written by `write_assets`.
+    fn add_asset(&mut self, path: &str, content: String, source: &Path) {
+        self.pending_assets.push(PendingAsset {
+            path: path.to_string(),
+            content,
+            source: source.to_path_buf(),
+        });
+    }
+
+    /// Records every queued asset in the asset manifest under its path
+    /// relative to the output dir, then writes them. Stylesheets can only
+    /// point at the fingerprinted urls of the files they import once all of
+    /// those are known, so nothing is written before the whole set is in.
+    async fn write_assets(&mut self, output_dir: &Path) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending_assets);
+        let assets = record_assets(pending, Arc::make_mut(&mut self.assets), self.fingerprint);
+
+        let mut written = 0;
+        let mut unchanged = 0;
+        for (output_path, asset) in assets {
+            let output_path = output_dir.join(output_path);
+            self.claim_output_path(&output_path, &asset.source)?;
+            remove_stale_fingerprints(output_dir, &asset.path, &output_path).await?;
+            if write_if_changed(&output_path, asset.content)
+                .await
+                .with_context(|| format!("Failed to write {}", asset.path))?
+            {
+                written += 1;
+            } else {
+                unchanged += 1;
+            }
+        }
+
+        println!(
+            "✓ Wrote assets: {} written, {} unchanged",
+            written, unchanged
+        );
+        Ok(())
+    }
+
+    fn add_aliases(&mut self, document: &Document, url: &str, source: &Path) -> Result<()> {
+        for alias in document.page.metadata.aliases.iter().flatten() {
+            self.redirects.push(Redirect {
+                from: normalize_alias(alias)
+                    .with_context(|| format!("Invalid alias in {}", source.display()))?,
+                to: url.to_string(),
+                source: source.to_path_buf(),
+            });
+        }
+        Ok(())
+    }
 }
 
 struct SyntectAdapter {
-    syntax_set: SyntaxSet,
+    syntax_set: &'static SyntaxSet,
+    unknown_languages: Mutex<Vec<String>>,
+    // Attributes of the block being rendered, from its pre tag
+    attributes: Mutex<CodeAttributes>,
 }
 
 impl SyntectAdapter {
-    fn new() -> Self {
-        Self {
-            syntax_set: SyntaxSet::load_defaults_newlines(),
-        }
+    fn new() -> Result<Self> {
+        Ok(Self {
+            syntax_set: syntax_set()?,
+            unknown_languages: Mutex::new(Vec::new()),
+            attributes: Mutex::new(CodeAttributes::default()),
+        })
     }
 }
 
@@ -64,31 +266,94 @@ impl SyntaxHighlighterAdapter for SyntectAdapter {
         code: &str,
     ) -> std::io::Result<()> {
         let lang = lang.unwrap_or("text");
-        let syntax = self.syntax_set
-            .find_syntax_by_token(lang)
-            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
-        
+        let syntax = find_syntax(self.syntax_set, lang).unwrap_or_else(|| {
+            if let Ok(mut unknown) = self.unknown_languages.lock() {
+                unknown.push(lang.to_string());
+            }
+            self.syntax_set.find_syntax_plain_text()
+        });
+
+        let attributes = self
+            .attributes
+            .lock()
+            .map(|mut attributes| std::mem::take(&mut *attributes))
+            .unwrap_or_default();
+        let (code, markers) = if attributes.diff {
+            split_diff(code)
+        } else {
+            (code.to_string(), Vec::new())
+        };
+
         let mut html_generator = ClassedHTMLGenerator::new_with_class_style(
             syntax, 
-            &self.syntax_set, 
+            self.syntax_set, 
             ClassStyle::Spaced
         );
-        
-        for line in LinesWithEndings::from(code) {
+
+        for line in LinesWithEndings::from(&code) {
             html_generator.parse_html_for_line_which_includes_newline(line)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                .map_err(std::io::Error::other)?;
+        }
+        let html = html_generator.finalize();
+
+        if !attributes.wraps_lines() {
+            write!(output, "{}", html)?;
+            return Ok(());
+        }
+        for (i, line) in split_highlighted_lines(&html).iter().enumerate() {
+            let mut class = String::from("line");
+            if attributes.is_highlighted(i + 1) {
+                class.push_str(" hl");
+            }
+            match markers.get(i) {
+                Some(DiffLine::Added) => class.push_str(" diff-add"),
+                Some(DiffLine::Removed) => class.push_str(" diff-remove"),
+                _ => {}
+            }
+            write!(
+                output,
+                "<span class=\"{}\"><span class=\"cl\">{}\n</span></span>",
+                class, line
+            )?;
         }
-        
-        write!(output, "{}", html_generator.finalize())?;
         Ok(())
     }
 
     fn write_pre_tag(
         &self,
         output: &mut dyn std::io::Write,
-        _attributes: HashMap<String, String>,
+        attributes: HashMap<String, String>,
     ) -> std::io::Result<()> {
-        output.write_all(b"<pre class=\"code\">")?;
+        let code_attributes = attributes
+            .get("data-meta")
+            .map(|meta| CodeAttributes::parse(meta))
+            .transpose()
+            .map_err(std::io::Error::other)?
+            .unwrap_or_default();
+
+        if let Some(title) = &code_attributes.title {
+            write!(
+                output,
+                "<div class=\"code-title\">{}</div>",
+                escape_attribute(title)
+            )?;
+        }
+        let mut class = String::from("code");
+        if code_attributes.linenos {
+            class.push_str(" linenos");
+        }
+        if code_attributes.diff {
+            class.push_str(" diff");
+        }
+        write!(output, "<pre class=\"{}\"", class)?;
+        if code_attributes.copy {
+            output.write_all(b" data-copy")?;
+        }
+        output.write_all(b">")?;
+
+        if let Ok(mut current) = self.attributes.lock() {
+            *current = code_attributes;
+        }
         Ok(())
     }
 
@@ -102,7 +367,64 @@ impl SyntaxHighlighterAdapter for SyntectAdapter {
     }
 }
 
-pub async fn build_content<P: AsRef<Path>>(include_draft: bool, output_dir: P) -> Result<()> {
+/// The options a build was started with, kept by `serve` so that rebuilds
+/// while it runs match them.
+#[derive(Debug, Clone, Copy)]
+pub struct BuildOptions {
+    pub include_drafts: bool,
+    pub include_future: bool,
+    pub profile: BuildProfile,
+    pub minify_html: bool,
+}
+
+/// Recompiles the scss sources after one of them changed and writes the
+/// stylesheets whose css differs. With fingerprinting on, pages refer to
+/// stylesheets by a hash of their content, so the whole site is rebuilt.
+pub async fn rebuild_stylesheets(options: BuildOptions, output_dir: &Path) -> Result<()> {
+    let config = get_config().await?;
+    if config.assets.fingerprint {
+        return build_content(
+            options.include_drafts,
+            options.include_future,
+            options.profile,
+            options.minify_html,
+            output_dir,
+        )
+        .await;
+    }
+
+    let static_dir = Path::new("static");
+    let minify = minify_assets(&config, options.profile);
+    let stylesheets = compile_stylesheets(static_dir, minify)?
+        .into_iter()
+        .map(|stylesheet| PendingAsset {
+            path: stylesheet.path,
+            content: stylesheet.css,
+            source: stylesheet.source,
+        })
+        .collect();
+
+    for (output_path, stylesheet) in
+        record_assets(stylesheets, &mut AssetManifest::default(), false)
+    {
+        if write_if_changed(&output_dir.join(output_path), stylesheet.content)
+            .await
+            .with_context(|| format!("Failed to write {}", stylesheet.path))?
+        {
+            println!("✓ Compiled stylesheet: {}", stylesheet.source.display());
+        }
+    }
+    precompress(output_dir, &config.compress).await?;
+    Ok(())
+}
+
+pub async fn build_content<P: AsRef<Path>>(
+    include_draft: bool,
+    include_future: bool,
+    profile: BuildProfile,
+    minify_html: bool,
+    output_dir: P,
+) -> Result<()> {
     let content_dir = get_content_dir()
         .await
         .context("Failed to get content directory")?;
@@ -112,102 +434,682 @@ pub async fn build_content<P: AsRef<Path>>(include_draft: bool, output_dir: P) -
         .await
         .context("Failed to create output dir")?;
 
-    let nav_items = get_nav_items(&content_dir)
+    let nav_items = get_nav_items(&content_dir, include_draft, include_future)
         .await
         .context("Failed to get navbar items")?;
+    let pages = collect_page_urls(content_dir, include_draft, include_future).await?;
 
+    let config = get_config().await?;
+    let static_dir = Path::new("static");
+    let mut state = BuildState {
+        profile,
+        fingerprint: config.assets.fingerprint,
+        minify_html: minify_html || config.html.minify,
+        images: ImageSet::new(&config.images.sizes),
+        pages,
+        ..Default::default()
+    };
+    // Assets go first so that pages can refer to them through the manifest
+    generate_syntax_themes(&mut state).await?;
+    compile_sass(static_dir, &mut state).await?;
+    create_asset_bundles(static_dir, &mut state).await?;
+    copy_static_content(static_dir, output_dir.as_ref(), &mut state).await?;
+    state.write_assets(output_dir.as_ref()).await?;
+    create_index_page(content_dir, output_dir.as_ref(), &nav_items, &mut state).await?;
+    create_static_pages(
+        content_dir,
+        output_dir.as_ref(),
+        &nav_items,
+        include_draft,
+        include_future,
+        &mut state,
+    )
+    .await?;
+    create_blog_categories(
+        content_dir,
+        output_dir.as_ref(),
+        &nav_items,
+        include_draft,
+        include_future,
+        &mut state,
+    )
+    .await?;
+    create_redirects(output_dir.as_ref(), &mut state).await?;
+    if state.fingerprint {
+        write_asset_manifest(output_dir.as_ref(), &state).await?;
+    }
+    // Runs even with compression off, to remove siblings of earlier builds
+    let compressed = precompress(output_dir.as_ref(), &config.compress).await?;
+    if compressed > 0 {
+        println!("✓ Compressed {} files", compressed);
+    }
+    Ok(())
+}
+
+fn minify_assets(config: &Config, profile: BuildProfile) -> bool {
+    config
+        .assets
+        .minify
+        .unwrap_or(profile == BuildProfile::Production)
+}
 
-    generate_syntax_themes(&output_dir.as_ref()).await?;
+async fn compile_sass(static_dir: &Path, state: &mut BuildState) -> Result<()> {
+    let config = get_config().await?;
+    let minify = minify_assets(&config, state.profile);
+
+    for stylesheet in compile_stylesheets(static_dir, minify)? {
+        state.add_asset(&stylesheet.path, stylesheet.css, &stylesheet.source);
+        println!("✓ Compiled stylesheet: {}", stylesheet.source.display());
+    }
 
-    let static_dir = Path::new("static");
-    create_index_page(&content_dir, &output_dir.as_ref(), &nav_items).await?;
-    create_static_pages(&content_dir, output_dir.as_ref(), &nav_items, include_draft).await?;
-    create_blog_categories(&content_dir, output_dir.as_ref(), &nav_items, include_draft).await?;
-    copy_static_content(&static_dir, output_dir.as_ref()).await?;
     Ok(())
 }
 
-async fn copy_static_content(static_dir: &Path, output_dir: &Path) -> Result<()> {
-    copy_dir(static_dir.join("images"), output_dir.join("images"))
-        .await
-        .context("failed to copy images")?;
+async fn create_asset_bundles(static_dir: &Path, state: &mut BuildState) -> Result<()> {
+    let config = get_config().await?;
+    let minify = minify_assets(&config, state.profile);
 
-    copy(static_dir.join("style.css"), output_dir.join("style.css"))
-        .await
-        .context("failed to copy style.css")?;
+    for (name, patterns) in &config.assets.bundles {
+        let bundle = create_bundle(static_dir, name, patterns, minify)
+            .await
+            .with_context(|| format!("Failed to create bundle: {}", name))?;
 
-    copy(static_dir.join("script.js"), output_dir.join("script.js"))
-        .await
-        .context("failed to copy script.js")?;
+        state.add_asset(name, bundle, static_dir);
+        println!("✓ Created bundle: {}", name);
+    }
 
     Ok(())
 }
 
-async fn generate_syntax_themes(output_dir: &Path) -> Result<()> {
-    let theme_set = ThemeSet::load_defaults();
-    
-    let dark_theme = &theme_set.themes["base16-mocha.dark"];
-    let css_dark = css_for_theme_with_class_style(dark_theme, ClassStyle::Spaced)
-        .context("Failed to generate dark theme CSS")?;
-    
-    write(output_dir.join("theme-dark.css"), css_dark).await
-        .context("Failed to write dark theme CSS")?;
-    
-    let light_theme = &theme_set.themes["base16-ocean.light"];
-    let css_light = css_for_theme_with_class_style(light_theme, ClassStyle::Spaced)
-        .context("Failed to generate light theme CSS")?;
-    
-    write(output_dir.join("theme-light.css"), css_light).await
-        .context("Failed to write light theme CSS")?;
-    
-    let main_css = r#"
-/* Syntax highlighting with automatic light/dark theme switching */
-@import url("theme-light.css") (prefers-color-scheme: light);
-@import url("theme-dark.css") (prefers-color-scheme: dark);
-
-/* Default to light theme for older browsers */
-@import url("theme-light.css");
-
-/* Code block styling */
-.code {
-    font-family: 'Fira Code', 'Monaco', 'Cascadia Code', 'Roboto Mono', monospace;
-    font-size: 0.9rem;
-    line-height: 1.5;
-    padding: 1rem;
-    border-radius: 0.375rem;
-    overflow-x: auto;
-    margin: 1rem 0;
-}
-
-/* Light theme body styling */
-@media (prefers-color-scheme: light) {
-    .code {
-        background-color: #fdf6e3;
-        border: 1px solid #eee8d5;
-    }
-}
-
-/* Dark theme body styling */
-@media (prefers-color-scheme: dark) {
-    .code {
-        background-color: #002b36;
-        border: 1px solid #073642;
-    }
-}
-"#;
-    
-    write(output_dir.join("syntax.css"), main_css).await
-        .context("Failed to write main syntax CSS")?;
-    
+async fn write_asset_manifest(output_dir: &Path, state: &BuildState) -> Result<()> {
+    let manifest = serde_json::to_string_pretty(state.assets.as_ref())
+        .context("Failed to serialize asset manifest")?;
+    write_output(&output_dir.join("asset-manifest.json"), manifest).await?;
+    println!("✓ Created asset-manifest.json");
     Ok(())
 }
 
-fn create_highlighted_content(input: &str, options: &Options) -> Result<String> {
-    let adapter = SyntectAdapter::new();
+async fn create_redirects(output_dir: &Path, state: &mut BuildState) -> Result<()> {
+    let config = get_config().await?;
+    let redirects = std::mem::take(&mut state.redirects);
+
+    for redirect in &redirects {
+        let output_path = permalink_output_path(output_dir, &redirect.from)?;
+        state.claim_output_path(&output_path, &redirect.source)?;
+        write_output(&output_path, redirect_stub(&redirect.to)).await?;
+        println!("✓ Created redirect: {} -> {}", redirect.from, redirect.to);
+    }
+
+    if config.redirects.netlify {
+        write_output(&output_dir.join("_redirects"), redirects_file(&redirects)).await?;
+        println!("✓ Created _redirects");
+    }
+    if config.redirects.nginx {
+        write_output(
+            &output_dir.join("nginx-redirects.map"),
+            nginx_map_file(&redirects),
+        )
+        .await?;
+        println!("✓ Created nginx-redirects.map");
+    }
+
+    Ok(())
+}
+
+fn is_asset(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("css" | "js")
+    )
+}
+
+/// Mirrors the whole static directory into the output directory, leaving
+/// out scss sources, files matching `assets.ignore` and files that haven't
+/// changed since the last build. Css and js files are recorded in the asset
+/// manifest, and images go through the image pipeline.
+async fn copy_static_content(
+    static_dir: &Path,
+    output_dir: &Path,
+    state: &mut BuildState,
+) -> Result<()> {
+    if !static_dir.exists() {
+        println!("No static directory found, skipping static files");
+        return Ok(());
+    }
+
+    let config = get_config().await?;
+    let mut ignore = GlobSetBuilder::new();
+    for pattern in &config.assets.ignore {
+        ignore.add(
+            Glob::new(pattern)
+                .with_context(|| format!("Invalid pattern in assets.ignore: {}", pattern))?,
+        );
+    }
+    let ignore = ignore.build().context("Failed to build assets.ignore")?;
+
+    let mut copied = 0;
+    let mut unchanged = 0;
+    let mut images = 0;
+
+    for entry in WalkDir::new(static_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        let rel_path = entry.path().strip_prefix(static_dir)?;
+        if is_scss(rel_path) || ignore.is_match(rel_path) {
+            continue;
+        }
+
+        if config.images.enabled && is_image(rel_path) {
+            let path = rel_path.to_string_lossy().replace('\\', "/");
+            let (image, outputs) = process_image(entry.path(), &path, output_dir, &config.images)
+                .await
+                .with_context(|| format!("Failed to process image: {}", entry.path().display()))?;
+            for output in outputs {
+                state.claim_output_path(&output, entry.path())?;
+            }
+            state.images.insert(format!("/{}", path), image);
+            images += 1;
+            continue;
+        }
+
+        if is_asset(rel_path) {
+            let content = read_to_string(entry.path())
+                .await
+                .with_context(|| format!("Failed to read {}", entry.path().display()))?;
+            let path = rel_path.to_string_lossy().replace('\\', "/");
+            state.add_asset(&path, content, entry.path());
+            continue;
+        }
+
+        let dest_path = output_dir.join(rel_path);
+        state.claim_output_path(&dest_path, entry.path())?;
+        if copy_if_changed(entry.path(), &dest_path).await? {
+            copied += 1;
+        } else {
+            unchanged += 1;
+        }
+    }
+
+    println!(
+        "✓ Copied static files: {} copied, {} unchanged, {} images processed",
+        copied, unchanged, images
+    );
+    Ok(())
+}
+
+async fn generate_syntax_themes(state: &mut BuildState) -> Result<()> {
+    let config = get_config().await?;
+    let source = Path::new("syntax themes");
+
+    for (path, css) in syntax_stylesheets(&config.highlight)? {
+        state.add_asset(path, css, source);
+    }
+
+    Ok(())
+}
+
+/// What rendering the markdown of a document depends on besides its content.
+struct RenderContext<'a> {
+    options: &'a Options<'a>,
+    images: &'a ImageSet,
+    admonitions: &'a AdmonitionsConfig,
+    /// Content file being rendered, which links to other pages are
+    /// resolved against.
+    path: &'a Path,
+    content_dir: &'a Path,
+    pages: &'a HashMap<PathBuf, String>,
+    /// Url of the page, which relative links in its summary are resolved
+    /// against.
+    url: &'a str,
+    /// Number of lines before the content in its file, like the front
+    /// matter, so errors can point at the line in the file.
+    line_offset: usize,
+    /// Whether the page is from a trusted section. Untrusted pages can't
+    /// include files.
+    trusted: bool,
+}
+
+fn create_highlighted_content(input: &str, context: &RenderContext) -> Result<RenderedMarkdown> {
+    let adapter = SyntectAdapter::new()?;
     let mut plugins = Plugins::default();
     plugins.render.codefence_syntax_highlighter = Some(&adapter);
 
-    Ok(markdown_to_html_with_plugins(input, options, &plugins))
+    let input = expand_containers(input, context.admonitions);
+    let arena = Arena::new();
+    let root = parse_document(&arena, &input, context.options);
+    let unknown_attributes = include_code(root, context.line_offset, context.trusted)?;
+    render_diagrams(&arena, root, context.line_offset, context.url)?;
+    render_math(&arena, root, context.line_offset)?;
+    render_admonitions(&arena, root, context.admonitions);
+    let headings = collect_headings(root, context.options);
+    let words = collect_words(root);
+    let unresolved_links = resolve_links(root, context);
+    replace_images(&arena, root, context.images, context.url);
+
+    let html = render_html(root, context.options, &plugins)?;
+    let summary = split_summary(root, context.url)
+        .map(|summary| render_html(summary, context.options, &plugins))
+        .transpose()?;
+
+    Ok(RenderedMarkdown {
+        html,
+        summary,
+        headings,
+        words,
+        unresolved_links,
+        unknown_attributes,
+        unknown_languages: adapter.unknown_languages.into_inner().unwrap_or_default(),
+    })
+}
+
+/// A node for markup the build generates, which is written out as is. Unlike
+/// html nodes it isn't dropped when `markdown.unsafe_html` is off.
+fn raw_node<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    literal: String,
+    start: LineColumn,
+) -> &'a AstNode<'a> {
+    arena.alloc(AstNode::new(RefCell::new(Ast::new(
+        NodeValue::Raw(literal),
+        start,
+    ))))
+}
+
+fn render_html<'a>(root: &'a AstNode<'a>, options: &Options, plugins: &Plugins) -> Result<String> {
+    let mut html = Vec::new();
+    format_html_with_plugins(root, options, &mut html, plugins)
+        .context("Failed to render markdown")?;
+    String::from_utf8(html).context("Rendered markdown is not valid UTF-8")
+}
+
+/// Cuts a document down to the blocks before its `<!-- more -->` marker.
+/// Markers in code blocks or inline html don't count. The summary is shown
+/// on listing pages, so relative urls in it are resolved against the url
+/// of the page.
+fn split_summary<'a>(root: &'a AstNode<'a>, url: &str) -> Option<&'a AstNode<'a>> {
+    let marker = root.children().find(|node| {
+        matches!(
+            &node.data.borrow().value,
+            NodeValue::HtmlBlock(block) if block.literal.trim() == SUMMARY_MARKER
+        )
+    })?;
+    while let Some(next) = marker.next_sibling() {
+        next.detach();
+    }
+    marker.detach();
+
+    for node in root.descendants() {
+        if let NodeValue::Link(link) | NodeValue::Image(link) = &mut node.data.borrow_mut().value {
+            link.url = resolve_relative_url(&link.url, url);
+        }
+    }
+    Some(root)
+}
+
+/// Points links to other content files at the url of their page, keeping
+/// any fragment, e.g. `../blog/post.md#setup`. Paths are relative to the
+/// file, or to the content dir when they start with `@/`. Returns the
+/// links that match no page that is built.
+fn resolve_links<'a>(root: &'a AstNode<'a>, context: &RenderContext) -> Vec<(usize, String)> {
+    let base = context.path.parent().unwrap_or(Path::new(""));
+    let mut unresolved = Vec::new();
+
+    for node in root.descendants() {
+        let mut ast = node.data.borrow_mut();
+        let line = ast.sourcepos.start.line + context.line_offset;
+        let NodeValue::Link(link) = &mut ast.value else {
+            continue;
+        };
+        let (path, fragment) = match link.url.split_once('#') {
+            Some((path, fragment)) => (path, Some(fragment)),
+            None => (link.url.as_str(), None),
+        };
+        if !path.ends_with(".md") || path.contains("://") {
+            continue;
+        }
+
+        let target = match path.strip_prefix("@/") {
+            Some(path) => context.content_dir.join(path),
+            None => base.join(path),
+        };
+        let Some(url) = context.pages.get(&normalize_path(&target)) else {
+            unresolved.push((line, link.url.clone()));
+            continue;
+        };
+        link.url = match fragment {
+            Some(fragment) => format!("{}#{}", url, fragment),
+            None => url.clone(),
+        };
+    }
+    unresolved
+}
+
+/// Collects the words of the prose in a document, skipping code blocks and
+/// raw html so that they don't inflate the word count.
+fn collect_words<'a>(root: &'a AstNode<'a>) -> Vec<String> {
+    let mut text = String::new();
+    for node in root.descendants() {
+        match node.data.borrow().value {
+            NodeValue::Text(ref literal) => text.push_str(literal),
+            NodeValue::Code(ref code) => text.push_str(&code.literal),
+            ref value if value.block() => text.push(' '),
+            NodeValue::SoftBreak | NodeValue::LineBreak => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().map(String::from).collect()
+}
+
+/// Uses the html before a `<!-- more -->` marker as the summary when there
+/// is one, otherwise the first `max_words` words of the text.
+fn create_summary(summary: Option<String>, words: &[String], max_words: usize) -> String {
+    if let Some(summary) = summary {
+        return summary;
+    }
+
+    let mut summary = words
+        .iter()
+        .take(max_words)
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(" ");
+    if words.len() > max_words {
+        summary.push('…');
+    }
+
+    format!("<p>{}</p>", tera::escape_html(&summary))
+}
+
+fn reading_time(word_count: usize, words_per_minute: usize) -> usize {
+    word_count.div_ceil(words_per_minute.max(1)).max(1)
+}
+
+/// Swaps markdown images that went through the image pipeline for their
+/// responsive `<picture>` markup. Relative urls, like those of images in a
+/// page bundle, are resolved against the url of the page.
+fn replace_images<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    images: &ImageSet,
+    url: &str,
+) {
+    let nodes = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::Image(_)))
+        .collect::<Vec<&AstNode>>();
+
+    for node in nodes {
+        let mut alt = Vec::new();
+        collect_text(node, &mut alt);
+        let ast = node.data.borrow();
+        let NodeValue::Image(link) = &ast.value else {
+            continue;
+        };
+        let Some(picture) = images.picture(
+            &resolve_relative_url(&link.url, url),
+            &String::from_utf8_lossy(&alt),
+            &link.title,
+        ) else {
+            continue;
+        };
+
+        let html = raw_node(arena, picture, ast.sourcepos.start);
+        drop(ast);
+        node.insert_before(html);
+        node.detach();
+    }
+}
+
+/// Replaces the content of code fences with a `file` attribute by the code
+/// they include. Returns the unknown attributes of every fence.
+fn include_code<'a>(
+    root: &'a AstNode<'a>,
+    line_offset: usize,
+    trusted: bool,
+) -> Result<Vec<(usize, String)>> {
+    let mut unknown = Vec::new();
+    for node in root.descendants() {
+        let mut ast = node.data.borrow_mut();
+        let line = ast.sourcepos.start.line + line_offset;
+        let NodeValue::CodeBlock(block) = &mut ast.value else {
+            continue;
+        };
+        let Some((_, meta)) = block.info.split_once(char::is_whitespace) else {
+            continue;
+        };
+        let attributes = CodeAttributes::parse(meta)
+            .with_context(|| format!("Invalid code block at line {}", line))?;
+        if attributes.file.is_some() && !trusted {
+            bail!(
+                "Invalid code block at line {}: files can't be included in untrusted sections",
+                line
+            );
+        }
+        let snippet = include_snippet(&attributes, Path::new("."))
+            .with_context(|| format!("Invalid code block at line {}", line))?;
+        if let Some(snippet) = snippet {
+            block.literal = snippet;
+        }
+        unknown.extend(attributes.unknown.into_iter().map(|key| (line, key)));
+    }
+    Ok(unknown)
+}
+
+/// Replaces diagram code fences like `dot` with inline svg, failing with
+/// every diagram in the document that doesn't render. Fences with the
+/// `nodiagram` attribute are left as code. Svg ids are keyed on the page
+/// `url` and the position of the fence.
+fn render_diagrams<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    line_offset: usize,
+    url: &str,
+) -> Result<()> {
+    let nodes = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::CodeBlock(_)))
+        .collect::<Vec<&AstNode>>();
+
+    let mut errors = Vec::new();
+    for (index, node) in nodes.into_iter().enumerate() {
+        let ast = node.data.borrow();
+        let NodeValue::CodeBlock(block) = &ast.value else {
+            continue;
+        };
+        let (language, meta) = block
+            .info
+            .split_once(char::is_whitespace)
+            .unwrap_or((&block.info, ""));
+        let line = ast.sourcepos.start.line + line_offset;
+        if !is_diagram(language)
+            || !CodeAttributes::parse(meta)
+                .with_context(|| format!("Invalid code block at line {}", line))?
+                .diagram
+        {
+            continue;
+        }
+
+        let svg = match render_diagram(&block.literal, &diagram_id(url, index)) {
+            Ok(svg) => svg,
+            Err(e) => {
+                errors.push(format!("line {}: {}", line, e));
+                continue;
+            }
+        };
+
+        let html = raw_node(arena, svg, ast.sourcepos.start);
+        drop(ast);
+        node.insert_before(html);
+        node.detach();
+    }
+
+    if !errors.is_empty() {
+        bail!("Failed to render diagrams:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Wraps blockquotes starting with an admonition marker like `[!NOTE]` in
+/// an `<aside>`. The rest of the marker's line is used as the title.
+fn render_admonitions<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    config: &AdmonitionsConfig,
+) {
+    let nodes = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::BlockQuote))
+        .collect::<Vec<&AstNode>>();
+
+    for node in nodes {
+        let Some(paragraph) = node
+            .first_child()
+            .filter(|child| matches!(child.data.borrow().value, NodeValue::Paragraph))
+        else {
+            continue;
+        };
+        let Some(marker) = paragraph.first_child() else {
+            continue;
+        };
+        let (kind, rest) = {
+            let ast = marker.data.borrow();
+            let NodeValue::Text(text) = &ast.value else {
+                continue;
+            };
+            let Some((kind, rest)) = parse_marker(text) else {
+                continue;
+            };
+            (kind.to_string(), rest.to_string())
+        };
+        let Some(default_title) = config.title(&kind) else {
+            continue;
+        };
+
+        // Take the rest of the marker's line as the title
+        if let NodeValue::Text(text) = &mut marker.data.borrow_mut().value {
+            *text = rest;
+        }
+        let mut title = Vec::new();
+        while let Some(child) = paragraph.first_child() {
+            let is_break = matches!(
+                child.data.borrow().value,
+                NodeValue::SoftBreak | NodeValue::LineBreak
+            );
+            if !is_break {
+                collect_text(child, &mut title);
+            }
+            child.detach();
+            if is_break {
+                break;
+            }
+        }
+        if paragraph.first_child().is_none() {
+            paragraph.detach();
+        }
+        let title = String::from_utf8_lossy(&title);
+        let title = match title.trim() {
+            "" => default_title,
+            title => title,
+        };
+
+        let start = node.data.borrow().sourcepos.start;
+        node.insert_before(raw_node(arena, admonition_open(&kind, title), start));
+        while let Some(child) = node.first_child() {
+            child.detach();
+            node.insert_before(child);
+        }
+        node.insert_before(raw_node(arena, "</aside>\n".to_string(), start));
+        node.detach();
+    }
+}
+
+/// Replaces `$…$` and `$$…$$` math with MathML, failing with every
+/// expression in the document that doesn't parse.
+fn render_math<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    line_offset: usize,
+) -> Result<()> {
+    let nodes = root
+        .descendants()
+        .filter(|node| matches!(node.data.borrow().value, NodeValue::Math(_)))
+        .collect::<Vec<&AstNode>>();
+
+    let mut errors = Vec::new();
+    for node in nodes {
+        let ast = node.data.borrow();
+        let NodeValue::Math(math) = &ast.value else {
+            continue;
+        };
+        let mathml = match math_to_mathml(&math.literal, math.display_math) {
+            Ok(mathml) => mathml,
+            Err(e) => {
+                errors.push(format!(
+                    "line {}: {}",
+                    ast.sourcepos.start.line + line_offset,
+                    e
+                ));
+                continue;
+            }
+        };
+
+        let html = raw_node(arena, mathml, ast.sourcepos.start);
+        drop(ast);
+        node.insert_before(html);
+        node.detach();
+    }
+
+    if !errors.is_empty() {
+        bail!("Failed to render math:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Collects every heading in document order as a flat list of toc entries.
+/// Ids are generated the same way comrak does for `header_ids` so that the
+/// toc links point at the anchors in the rendered html.
+fn collect_headings<'a>(root: &'a AstNode<'a>, options: &Options) -> Vec<TocEntry> {
+    let prefix = options.extension.header_ids.as_deref().unwrap_or("");
+    let mut anchorizer = Anchorizer::new();
+    let mut headings = Vec::new();
+
+    for node in root.descendants() {
+        let level = match node.data.borrow().value {
+            NodeValue::Heading(ref heading) => heading.level,
+            _ => continue,
+        };
+
+        let mut text = Vec::new();
+        collect_text(node, &mut text);
+        let title = String::from_utf8_lossy(&text).to_string();
+
+        headings.push(TocEntry {
+            level,
+            id: format!("{}{}", prefix, anchorizer.anchorize(title.clone())),
+            title,
+            children: Vec::new(),
+        });
+    }
+
+    headings
+}
+
+fn build_toc(headings: Vec<TocEntry>, depth: u8) -> Vec<TocEntry> {
+    let mut toc = Vec::new();
+    for heading in headings.into_iter().filter(|h| h.level <= depth) {
+        insert_toc_entry(&mut toc, heading);
+    }
+    toc
+}
+
+fn insert_toc_entry(entries: &mut Vec<TocEntry>, entry: TocEntry) {
+    match entries.last_mut() {
+        Some(last) if last.level < entry.level => insert_toc_entry(&mut last.children, entry),
+        _ => entries.push(entry),
+    }
 }
 
 async fn create_blog_categories(
@@ -215,7 +1117,11 @@ async fn create_blog_categories(
     output_dir: &Path,
     nav_items: &[NavItem],
     include_drafts: bool,
+    include_future: bool,
+    state: &mut BuildState,
 ) -> Result<()> {
+    let config = get_config().await?;
+
     for entry in WalkDir::new(content_dir)
         .min_depth(1)
         .max_depth(1)
@@ -251,48 +1157,49 @@ async fn create_blog_categories(
                 )
             })?;
 
-            let document = parse_content(&file_content, post_entry.path()).await?;
-            if document.metadata.draft.unwrap_or(false) && !include_drafts {
+            let page = parse_page(&file_content, post_entry.path()).await?;
+            if !is_published(&page, include_drafts, include_future) {
                 continue;
             }
 
-            let slug = get_slug(post_entry.path()).await.with_context(|| {
-                format!(
-                    "Failed to get slug for blog post: {}",
-                    post_entry.path().to_string_lossy()
-                )
-            })?;
-
-            let post_content = render_page(&document, "static.html", nav_items)
-                .await
-                .with_context(|| format!("Failed to process blog post: {}", slug))?;
+            let slug = page.slug.clone();
+            let bundle_dir = get_bundle_dir(post_entry.path(), entry.path());
+            let url = page_url(&config, &dir_name, &page, bundle_dir.is_some())?;
+            let post_file_path = permalink_output_path(output_dir, &url)
+                .with_context(|| format!("Invalid url for {}", post_entry.path().display()))?;
+            state.claim_output_path(&post_file_path, post_entry.path())?;
+            // Bundle images have to be processed before the page shows them
+            if let Some(bundle_dir) = bundle_dir {
+                copy_bundle_files(bundle_dir, output_dir, &post_file_path, state).await?;
+            }
+            let document = render_document(page, post_entry.path(), &url, state).await?;
+            state.add_aliases(&document, &url, post_entry.path())?;
 
-            let post_file_path = category_dir.join(format!("{}.html", slug));
+            let post_content =
+                render_page(&document, &url, "static.html", nav_items, state)
+                    .await
+                    .with_context(|| format!("Failed to process blog post: {}", slug))?;
 
-            write(&post_file_path, &post_content)
+            write_output(&post_file_path, &post_content)
                 .await
                 .with_context(|| format!("Failed to write blog post file: {}", slug))?;
 
-            let title = document.metadata.title.clone();
-
-            let tags = document.metadata.tags.as_ref().map(|tags_str| {
-                tags_str
-                    .split(',')
-                    .map(|tag| tag.trim().to_string())
-                    .filter(|tag| !tag.is_empty())
-                    .collect::<Vec<String>>()
-            });
+            let title = document.page.metadata.title.clone();
 
             posts.push(PostInfo {
                 slug: slug.clone(),
                 title,
-                date: document.metadata.date.clone(),
-                description: document.metadata.description.clone(),
-                tags,
-                url: format!("/{}/{}.html", dir_name, &slug),
+                date: document.page.date,
+                updated: document.page.updated,
+                description: document.page.metadata.description.clone(),
+                tags: document.page.metadata.tags.clone(),
+                url: url.clone(),
+                summary: document.summary.clone(),
+                word_count: document.word_count,
+                reading_time: document.reading_time,
             });
 
-            println!("✓ Created blog post: {}/{}.html", dir_name, slug);
+            println!("✓ Created blog post: {}", url);
         }
 
         posts.sort_by(|a, b| match (&b.date, &a.date) {
@@ -303,9 +1210,11 @@ async fn create_blog_categories(
         });
 
         // Category index uses blog.html template (the listing template)
-        let index_content = create_category_index(dir_name.as_ref(), &posts, nav_items).await?;
+        let index_content =
+            create_category_index(dir_name.as_ref(), &posts, nav_items, state).await?;
 
         let index_path = category_dir.join("index.html");
+        state.claim_output_path(&index_path, entry.path())?;
 
         write(&index_path, index_content)
             .await
@@ -324,6 +1233,7 @@ async fn create_category_index(
     category: &str,
     posts: &[PostInfo],
     nav_items: &[NavItem],
+    state: &BuildState,
 ) -> Result<String> {
     // Use blog.html template for category listings
     let template_file = Path::new("templates").join("index.html");
@@ -331,9 +1241,8 @@ async fn create_category_index(
         .await
         .with_context(|| format!("Failed to read template file: {:?}", template_file))?;
 
-    let mut tera = Tera::default();
-    tera.add_raw_template("category_index", &template_content)?;
     let config = get_config().await?;
+    let tera = create_tera("category_index", &template_content, &config, &state.assets)?;
 
     let mut context = tera::Context::new();
     context.insert("heading", &config.project);
@@ -350,7 +1259,7 @@ async fn create_category_index(
         .render("category_index", &context)
         .context("Failed to render category index template")?;
 
-    Ok(rendered)
+    Ok(minify_page(rendered, state.minify_html))
 }
 
 async fn create_static_pages(
@@ -358,6 +1267,8 @@ async fn create_static_pages(
     output_dir: &Path,
     nav_items: &[NavItem],
     include_drafts: bool,
+    include_future: bool,
+    state: &mut BuildState,
 ) -> Result<()> {
     let static_dir = content_dir.join("static");
 
@@ -366,6 +1277,8 @@ async fn create_static_pages(
         return Ok(());
     }
 
+    let config = get_config().await?;
+
     for entry in WalkDir::new(&static_dir)
         .into_iter()
         .filter_map(|e| e.ok())
@@ -375,38 +1288,133 @@ async fn create_static_pages(
             .await
             .with_context(|| format!("Failed to read file: {}", entry.path().to_string_lossy()))?;
 
-        let document = parse_content(&file_content, entry.path()).await?;
-        if document.metadata.draft.unwrap_or(false) && !include_drafts {
+        let page = parse_page(&file_content, entry.path()).await?;
+        if !is_published(&page, include_drafts, include_future) {
             continue;
         }
 
-        let slug = get_slug(entry.path()).await.with_context(|| {
-            format!(
-                "Failed to get slug of file: {}",
-                entry.path().to_string_lossy()
-            )
-        })?;
+        let bundle_dir = get_bundle_dir(entry.path(), &static_dir);
+        let url = page_url(&config, "static", &page, bundle_dir.is_some())?;
+        let output_path = permalink_output_path(output_dir, &url)
+            .with_context(|| format!("Invalid url for {}", entry.path().display()))?;
+        state.claim_output_path(&output_path, entry.path())?;
+        // Bundle images have to be processed before the page shows them
+        if let Some(bundle_dir) = bundle_dir {
+            copy_bundle_files(bundle_dir, output_dir, &output_path, state).await?;
+        }
+        let document = render_document(page, entry.path(), &url, state).await?;
+        let slug = &document.page.slug;
+        state.add_aliases(&document, &url, entry.path())?;
 
         // Static pages use static.html template and are stored at root
-        let content = render_page(&document, "static.html", nav_items)
+        let content = render_page(&document, &url, "static.html", nav_items, state)
             .await
             .with_context(|| format!("Failed to render static page: {}", slug))?;
 
-        let output_path = output_dir.join(format!("{}.html", slug));
-        write(&output_path, content)
+        write_output(&output_path, content)
             .await
             .with_context(|| format!("Failed to create static page: {}", slug))?;
 
-        println!("✓ Created static page: {}.html", slug);
+        println!("✓ Created static page: {}", url);
+    }
+
+    Ok(())
+}
+
+fn page_url(config: &Config, section: &str, page: &Page, bundle: bool) -> Result<String> {
+    let url = resolve_permalink(
+        permalink_pattern(config, section),
+        section,
+        &page.slug,
+        page.date.as_ref(),
+    )?;
+
+    // Bundles always get a directory of their own so that their files can
+    // sit next to the page and relative links keep working
+    if bundle && !url.ends_with('/') {
+        return Ok(format!("{}/", url.trim_end_matches(".html")));
+    }
+    Ok(url)
+}
+
+/// Copies every non markdown file of a page bundle next to the rendered page,
+/// passing images through the image pipeline. Directories with an
+/// `index.md` of their own are nested bundles, whose files belong to their
+/// own page.
+async fn copy_bundle_files(
+    bundle_dir: &Path,
+    output_dir: &Path,
+    page_output_path: &Path,
+    state: &mut BuildState,
+) -> Result<()> {
+    let Some(page_dir) = page_output_path.parent() else {
+        return Ok(());
+    };
+    let config = get_config().await?;
+
+    for entry in WalkDir::new(bundle_dir)
+        .into_iter()
+        .filter_entry(|e| {
+            e.depth() == 0 || !e.file_type().is_dir() || !e.path().join(BUNDLE_INDEX).exists()
+        })
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.path().extension().and_then(|ext| ext.to_str()) != Some("md"))
+    {
+        let dest_path = page_dir.join(entry.path().strip_prefix(bundle_dir)?);
+
+        if config.images.enabled && is_image(&dest_path) {
+            let path = dest_path
+                .strip_prefix(output_dir)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            let (image, outputs) = process_image(entry.path(), &path, output_dir, &config.images)
+                .await
+                .with_context(|| format!("Failed to process image: {}", entry.path().display()))?;
+            for output in outputs {
+                state.claim_output_path(&output, entry.path())?;
+            }
+            state.images.insert(format!("/{}", path), image);
+            continue;
+        }
+
+        state.claim_output_path(&dest_path, entry.path())?;
+        copy_if_changed(entry.path(), &dest_path).await?;
     }
 
     Ok(())
 }
 
+async fn write_output<C: AsRef<[u8]>>(output_path: &Path, content: C) -> Result<()> {
+    if let Some(parent) = output_path.parent() {
+        create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    write(output_path, content)
+        .await
+        .with_context(|| format!("Failed to write file: {}", output_path.display()))
+}
+
+/// Drafts and pages scheduled for the future are left out unless asked for,
+/// expired pages are always left out.
+fn is_published(page: &Page, include_drafts: bool, include_future: bool) -> bool {
+    let now = Utc::now();
+
+    if page.metadata.draft.unwrap_or(false) && !include_drafts {
+        return false;
+    }
+    if page.date.is_some_and(|date| date > now) && !include_future {
+        return false;
+    }
+    page.expires.is_none_or(|expires| expires > now)
+}
+
 async fn create_index_page(
     content_dir: &Path,
     output_dir: &Path,
     nav_items: &[NavItem],
+    state: &mut BuildState,
 ) -> Result<()> {
     let index_path = content_dir.join("index.md");
 
@@ -415,11 +1423,16 @@ async fn create_index_page(
     }
 
     let file_content = read_to_string(&index_path).await?;
-    let document = parse_content(&file_content, &index_path).await?;
+    let page = parse_page(&file_content, &index_path).await?;
+    let document = render_document(page, &index_path, "/", state).await?;
 
-    let content = render_page(&document, "static.html", nav_items).await?;
+    let output_path = output_dir.join("index.html");
+    state.claim_output_path(&output_path, &index_path)?;
+    state.add_aliases(&document, "/", &index_path)?;
 
-    write(output_dir.join("index.html"), content)
+    let content = render_page(&document, "/", "static.html", nav_items, state).await?;
+
+    write(&output_path, content)
         .await
         .context("failed to write index.html")?;
 
@@ -427,22 +1440,45 @@ async fn create_index_page(
     Ok(())
 }
 
-async fn render_page(document: &Document, template: &str, nav_items: &[NavItem]) -> Result<String> {
+/// Creates a Tera instance for a single template, with the filters and
+/// functions that every template can use.
+fn create_tera(
+    name: &str,
+    template: &str,
+    config: &Config,
+    assets: &Arc<AssetManifest>,
+) -> Result<Tera> {
+    let mut tera = Tera::default();
+    tera.add_raw_template(name, template)?;
+    tera.register_filter("date", DateFilter::new(&config.date)?);
+    tera.register_function("asset_url", AssetUrl::new(assets.clone()));
+    tera.register_function("asset_integrity", AssetIntegrity::new(assets.clone()));
+    Ok(tera)
+}
+
+async fn render_page(
+    document: &Document,
+    url: &str,
+    template: &str,
+    nav_items: &[NavItem],
+    state: &BuildState,
+) -> Result<String> {
     let templ = read_to_string(Path::new("templates").join(template))
         .await
         .with_context(|| format!("Failed to read template file: {}", template))?;
     let config = get_config().await.context("Failed to get project name")?;
 
-    let mut tera = Tera::default();
-    tera.add_raw_template("document", &templ)?;
+    let tera = create_tera("document", &templ, &config, &state.assets)?;
 
     let mut context = tera::Context::new();
 
     context.insert("heading", &config.project);
-    context.insert("title", &document.metadata.title);
+    context.insert("title", &document.page.metadata.title);
+    context.insert("url", url);
     context.insert(
         "author",
         &document
+            .page
             .metadata
             .author
             .as_deref()
@@ -450,83 +1486,331 @@ async fn render_page(document: &Document, template: &str, nav_items: &[NavItem])
     );
     context.insert(
         "description",
-        &document.metadata.description.as_deref().unwrap_or(""),
+        &document.page.metadata.description.as_deref().unwrap_or(""),
     );
     context.insert("content", &document.html_content);
-    context.insert("raw_content", &document.content);
+    context.insert("raw_content", &document.page.content);
     context.insert("navbar", nav_items);
-    if let Some(date) = &document.metadata.date {
-        context.insert("date", date);
-    }
-    if let Some(tags) = &document.metadata.tags {
-        let tags_vec: Vec<String> = tags
-            .split(',')
-            .map(|tag| tag.trim().to_string())
-            .filter(|tag| !tag.is_empty())
-            .collect();
-        context.insert("tags", &tags_vec);
-    }
-    if let Some(extra) = &document.metadata.extra {
+    context.insert("toc", &document.toc);
+    context.insert("summary", &document.summary);
+    context.insert("word_count", &document.word_count);
+    context.insert("reading_time", &document.reading_time);
+    if let Some(date) = &document.page.date {
+        context.insert("date", &date.to_rfc3339());
+    }
+    if let Some(updated) = &document.page.updated {
+        context.insert("updated", &updated.to_rfc3339());
+    }
+    if let Some(tags) = &document.page.metadata.tags {
+        context.insert("tags", tags);
+    }
+    if let Some(extra) = &document.page.metadata.extra {
+        context.insert("extra", extra);
         for (key, value) in extra {
-            context.insert(key, value);
+            if !RESERVED_TEMPLATE_VARIABLES.contains(&key.as_str()) {
+                context.insert(key, value);
+            }
         }
     }
     let rendered = tera
         .render("document", &context)
         .context("Failed to render template")?;
 
-    Ok(rendered)
+    Ok(minify_page(rendered, state.minify_html))
 }
 
-async fn parse_content(input: &str, path: &Path) -> Result<Document> {
+/// Strips the whitespace and comments Tera leaves behind. Text in `<pre>`
+/// is kept by the minifier, and scripts are left alone so inline code
+/// never changes behaviour.
+fn minify_page(html: String, minify: bool) -> String {
+    if !minify {
+        return html;
+    }
+
+    let cfg = minify_html::Cfg {
+        keep_closing_tags: true,
+        keep_html_and_head_opening_tags: true,
+        minify_css: true,
+        minify_js: false,
+        ..minify_html::Cfg::spec_compliant()
+    };
+    String::from_utf8(minify_html::minify(html.as_bytes(), &cfg)).unwrap_or(html)
+}
+
+/// The section of a content file, its top level directory in the content
+/// dir. Files at the root like `index.md` have none.
+fn content_section<'a>(path: &'a Path, content_dir: &str) -> Option<&'a str> {
+    let mut components = path.strip_prefix(content_dir).ok()?.components();
+    let section = components.next()?.as_os_str().to_str()?;
+    components.next().map(|_| section)
+}
+
+fn markdown_options(markdown: &MarkdownConfig) -> Options<'static> {
+    let mut options = Options::default();
+    options.extension.strikethrough = markdown.strikethrough;
+    options.extension.tagfilter = markdown.tagfilter;
+    options.extension.table = markdown.table;
+    options.extension.autolink = markdown.autolink;
+    options.extension.tasklist = markdown.tasklist;
+    options.extension.superscript = markdown.superscript;
+    options.extension.header_ids = Some("user-content-".to_string());
+    options.extension.footnotes = markdown.footnotes;
+    options.extension.description_lists = markdown.description_lists;
+    options.extension.front_matter_delimiter = Some("---".to_string());
+    options.extension.math_dollars = markdown.math;
+    options.parse.smart = markdown.smart;
+    options.parse.default_info_string = Some("text".to_string());
+    options.render.hardbreaks = markdown.hardbreaks;
+    options.render.github_pre_lang = true;
+    options.render.full_info_string = true;
+    options.render.width = markdown.width;
+    options.render.unsafe_ = markdown.unsafe_html;
+    options
+}
+
+/// Reads the front matter of a content file, leaving its markdown for
+/// `render_document`.
+async fn parse_page(input: &str, path: &Path) -> Result<Page> {
     let matter = Matter::<YAML>::new();
     let result = matter
         .parse::<FrontMatter>(input)
-        .context("Failed to parse frontmatter in markdown")?;
+        .with_context(|| format!("Failed to parse frontmatter in: {}", path.display()))?;
+    let slug = resolve_slug(result.data.as_ref(), path);
     let metadata = if let Some(data) = result.data {
         data
     } else {
         FrontMatter {
             title: "Untitled".to_string(),
-            author: None,
-            date: None,
-            tags: None,
-            description: None,
-            slug: get_slug(path).await?,
-            draft: None,
-            extra: None,
+            ..Default::default()
         }
     };
 
-    let mut options = Options::default();
-    options.extension.strikethrough = true;
-    options.extension.tagfilter = true;
-    options.extension.table = true;
-    options.extension.autolink = true;
-    options.extension.tasklist = true;
-    options.extension.superscript = true;
-    options.extension.header_ids = Some("user-content-".to_string());
-    options.extension.footnotes = true;
-    options.extension.description_lists = true;
-    options.extension.front_matter_delimiter = Some("---".to_string());
-    options.parse.smart = true;
-    options.parse.default_info_string = Some("text".to_string());
-    options.render.hardbreaks = false;
-    options.render.github_pre_lang = true;
-    options.render.width = 80;
-    options.render.unsafe_ = true;
+    let config = get_config().await?;
+    let timezone = parse_timezone(&config.date.timezone)?;
+    let date = metadata
+        .date
+        .as_deref()
+        .map(|date| parse_date(date, timezone))
+        .transpose()
+        .with_context(|| format!("Failed to parse date in: {}", path.display()))?;
+    let updated = metadata
+        .updated
+        .as_deref()
+        .map(|updated| parse_date(updated, timezone))
+        .transpose()
+        .with_context(|| format!("Failed to parse updated date in: {}", path.display()))?;
+    let expires = metadata
+        .expires
+        .as_deref()
+        .map(|expires| parse_date(expires, timezone))
+        .transpose()
+        .with_context(|| format!("Failed to parse expiry date in: {}", path.display()))?;
+
+    // The front matter isn't part of the rendered content, which is made of
+    // the last lines of the file
+    let line_offset = input
+        .lines()
+        .count()
+        .saturating_sub(result.content.split('\n').count());
+
+    Ok(Page {
+        metadata,
+        slug,
+        content: result.content,
+        line_offset,
+        date,
+        updated,
+        expires,
+    })
+}
+
+async fn render_document(
+    mut page: Page,
+    path: &Path,
+    url: &str,
+    state: &BuildState,
+) -> Result<Document> {
+    let config = get_config().await?;
+    let markdown = match &page.metadata.markdown {
+        Some(overrides) => config.markdown.with_overrides(overrides),
+        None => config.markdown.clone(),
+    };
+    let options = markdown_options(&markdown);
+    let section = content_section(path, &config.content_dir).unwrap_or_default();
+    let trusted = config.sanitize.trust(section) == TrustLevel::Trusted;
+    // The slug ends up in urls that templates show as is
+    if !trusted && slugify(&page.slug) != page.slug {
+        bail!(
+            "The slug '{}' of {} can only contain lowercase letters, digits and dashes, as its section is untrusted",
+            page.slug,
+            path.display()
+        );
+    }
+
+    let context = RenderContext {
+        options: &options,
+        images: &state.images,
+        admonitions: &config.admonitions,
+        path,
+        content_dir: Path::new(&config.content_dir),
+        pages: &state.pages,
+        url,
+        line_offset: page.line_offset,
+        trusted,
+    };
+    let rendered = create_highlighted_content(&page.content, &context)
+        .with_context(|| format!("Failed to render markdown in: {}", path.display()))?;
+    for language in &rendered.unknown_languages {
+        warn_unknown_language(path, language);
+    }
+    for key in page.metadata.extra.iter().flat_map(|extra| extra.keys()) {
+        if RESERVED_TEMPLATE_VARIABLES.contains(&key.as_str()) {
+            eprintln!(
+                "⚠ Extra '{}' in {} is only available as extra.{}, as it is a built-in template variable",
+                key,
+                path.display(),
+                key
+            );
+        }
+    }
+    for (line, attribute) in &rendered.unknown_attributes {
+        eprintln!(
+            "⚠ Ignoring unknown code block attribute '{}' at line {} of {}",
+            attribute,
+            line,
+            path.display()
+        );
+    }
+    for (line, link) in &rendered.unresolved_links {
+        eprintln!(
+            "⚠ Link to '{}' at line {} of {} matches no page that is built",
+            link,
+            line,
+            path.display()
+        );
+    }
+
+    let toc = if page.metadata.toc.unwrap_or(config.toc.enabled) {
+        build_toc(
+            rendered.headings,
+            page.metadata.toc_depth.unwrap_or(config.toc.depth),
+        )
+    } else {
+        Vec::new()
+    };
 
-    let html_content = create_highlighted_content(&result.content, &options)?;
+    let mut summary = create_summary(rendered.summary, &rendered.words, config.summary.words);
+    let word_count = rendered.words.len();
+
+    let mut html_content = rendered.html;
+    if !trusted {
+        let (html, stripped) = sanitize_html(&html_content, &config.sanitize);
+        report_stripped(path, &stripped);
+        html_content = html;
+        summary = sanitize_html(&summary, &config.sanitize).0;
+        escape_front_matter(&mut page.metadata);
+        page.content = tera::escape_html(&page.content);
+    }
 
     Ok(Document {
-        metadata,
-        content: result.content,
+        page,
         html_content,
+        toc,
+        summary,
+        word_count,
+        reading_time: reading_time(word_count, config.summary.words_per_minute),
     })
 }
 
-async fn get_nav_items<P: AsRef<Path>>(content_dir: P) -> Result<Vec<NavItem>> {
+/// Escapes the front matter of an untrusted page, as templates show it
+/// without escaping.
+fn escape_front_matter(metadata: &mut FrontMatter) {
+    metadata.title = tera::escape_html(&metadata.title);
+    for value in [&mut metadata.author, &mut metadata.description]
+        .into_iter()
+        .flatten()
+    {
+        *value = tera::escape_html(value);
+    }
+    for tag in metadata.tags.iter_mut().flatten() {
+        *tag = tera::escape_html(tag);
+    }
+    for value in metadata
+        .extra
+        .iter_mut()
+        .flat_map(|extra| extra.values_mut())
+    {
+        escape_json(value);
+    }
+}
+
+fn escape_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) => *text = tera::escape_html(text),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(escape_json),
+        serde_json::Value::Object(values) => values.values_mut().for_each(escape_json),
+        _ => {}
+    }
+}
+
+/// Reads the front matter of every content file for the url of its page,
+/// so that links between pages can be resolved before any is rendered.
+async fn collect_page_urls(
+    content_dir: &Path,
+    include_drafts: bool,
+    include_future: bool,
+) -> Result<HashMap<PathBuf, String>> {
+    let config = get_config().await?;
+    let mut urls = HashMap::new();
+    urls.insert(
+        normalize_path(&content_dir.join("index.md")),
+        "/".to_string(),
+    );
+
+    for section in WalkDir::new(content_dir)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+        .filter(|e| {
+            e.file_name()
+                .to_str()
+                .is_some_and(|name| !name.starts_with('.'))
+        })
+    {
+        let section_name = section.file_name().to_string_lossy();
+        for entry in WalkDir::new(section.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().and_then(|ext| ext.to_str()) == Some("md"))
+        {
+            let file_content = read_to_string(entry.path())
+                .await
+                .with_context(|| format!("Failed to read file: {}", entry.path().display()))?;
+            let page = parse_page(&file_content, entry.path()).await?;
+            if !is_published(&page, include_drafts, include_future) {
+                continue;
+            }
+
+            let bundle = get_bundle_dir(entry.path(), section.path()).is_some();
+            urls.insert(
+                normalize_path(entry.path()),
+                page_url(&config, &section_name, &page, bundle)?,
+            );
+        }
+    }
+
+    Ok(urls)
+}
+
+async fn get_nav_items<P: AsRef<Path>>(
+    content_dir: P,
+    include_drafts: bool,
+    include_future: bool,
+) -> Result<Vec<NavItem>> {
     let mut nav_items: Vec<NavItem> = Vec::new();
+    let config = get_config().await?;
 
     let static_dir = content_dir.as_ref().join("static");
     if static_dir.exists() {
@@ -535,10 +1819,24 @@ async fn get_nav_items<P: AsRef<Path>>(content_dir: P) -> Result<Vec<NavItem>> {
             .filter_map(|e| e.ok())
             .filter(|e| e.path().extension().and_then(|ext| ext.to_str()) == Some("md"))
         {
-            let slug = get_slug(entry.path()).await?;
+            let file_content = read_to_string(entry.path())
+                .await
+                .with_context(|| format!("Failed to read file: {}", entry.path().display()))?;
+            // Only the url is needed here, so the markdown isn't rendered
+            let page = parse_page(&file_content, entry.path()).await?;
+            if !is_published(&page, include_drafts, include_future) {
+                continue;
+            }
+
             nav_items.push(NavItem {
-                name: slug,
-                nav_type: NavType::FILE,
+                url: page_url(
+                    &config,
+                    "static",
+                    &page,
+                    get_bundle_dir(entry.path(), &static_dir).is_some(),
+                )?,
+                name: page.slug,
+                nav_type: NavType::File,
             });
         }
     }
@@ -552,7 +1850,8 @@ async fn get_nav_items<P: AsRef<Path>>(content_dir: P) -> Result<Vec<NavItem>> {
         .filter(|entry| entry.file_name() != "static")
         .map(|entry| NavItem {
             name: entry.file_name().to_string_lossy().into(),
-            nav_type: NavType::DIR,
+            nav_type: NavType::Dir,
+            url: format!("/{}/", entry.file_name().to_string_lossy()),
         })
         .collect::<Vec<NavItem>>();
 
@@ -561,3 +1860,224 @@ async fn get_nav_items<P: AsRef<Path>>(content_dir: P) -> Result<Vec<NavItem>> {
     Ok(nav_items)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::{ImageSource, ProcessedImage};
+
+    fn heading(level: u8, title: &str) -> TocEntry {
+        TocEntry {
+            level,
+            id: title.to_lowercase(),
+            title: title.to_string(),
+            children: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn build_toc_nests_headings_under_shallower_ones() {
+        let toc = build_toc(
+            vec![
+                heading(2, "A"),
+                heading(3, "A1"),
+                heading(4, "Deep"),
+                heading(2, "B"),
+            ],
+            3,
+        );
+
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].title, "A");
+        assert_eq!(toc[0].children.len(), 1);
+        assert_eq!(toc[0].children[0].title, "A1");
+        assert!(toc[0].children[0].children.is_empty());
+        assert_eq!(toc[1].title, "B");
+    }
+
+    fn summary_html(markdown: &str, url: &str) -> Option<String> {
+        let options = markdown_options(&MarkdownConfig::default());
+        let arena = Arena::new();
+        let root = parse_document(&arena, markdown, &options);
+        split_summary(root, url)
+            .map(|summary| render_html(summary, &options, &Plugins::default()).unwrap())
+    }
+
+    #[test]
+    fn split_summary_cuts_at_the_marker_block() {
+        let summary = summary_html(
+            "Intro ![a](a.png)\n\n```\n<!-- more -->\n```\n\n<!-- more -->\n\nRest\n",
+            "/blog/post/",
+        )
+        .unwrap();
+        assert!(summary.contains("src=\"/blog/post/a.png\""));
+        assert!(summary.contains("&lt;!-- more --&gt;"));
+        assert!(!summary.contains("Rest"));
+    }
+
+    #[test]
+    fn split_summary_ignores_markers_in_code_and_inline_html() {
+        assert!(summary_html("```\n<!-- more -->\n```\n", "/").is_none());
+        assert!(summary_html("Intro <!-- more --> rest\n", "/").is_none());
+    }
+
+    #[test]
+    fn resolve_links_points_content_links_at_pages() {
+        let options = markdown_options(&MarkdownConfig::default());
+        let pages = HashMap::from([
+            (
+                PathBuf::from("content/static/about.md"),
+                "/about/".to_string(),
+            ),
+            (
+                PathBuf::from("content/blog/post.md"),
+                "/blog/post.html".to_string(),
+            ),
+        ]);
+        let context = RenderContext {
+            options: &options,
+            images: &ImageSet::default(),
+            admonitions: &AdmonitionsConfig::default(),
+            path: Path::new("content/blog/other.md"),
+            content_dir: Path::new("content"),
+            pages: &pages,
+            url: "/blog/other.html",
+            line_offset: 4,
+            trusted: true,
+        };
+        let arena = Arena::new();
+        let root = parse_document(
+            &arena,
+            "[a](../static/about.md#team) [b](@/blog/post.md) [c](post.md)\n\n[d](gone.md)\n",
+            &options,
+        );
+
+        assert_eq!(
+            resolve_links(root, &context),
+            vec![(7, "gone.md".to_string())]
+        );
+        let html = render_html(root, &options, &Plugins::default()).unwrap();
+        assert!(html.contains("href=\"/about/#team\""));
+        assert_eq!(html.matches("href=\"/blog/post.html\"").count(), 2);
+    }
+
+    #[test]
+    fn generated_markup_survives_without_unsafe_html() {
+        let options = markdown_options(&MarkdownConfig {
+            unsafe_html: false,
+            math: true,
+            ..MarkdownConfig::default()
+        });
+        let mut images = ImageSet::new("100vw");
+        images.insert(
+            "/images/a.png".to_string(),
+            ProcessedImage {
+                width: 10,
+                height: 10,
+                sources: vec![ImageSource {
+                    url: "/images/a.png".to_string(),
+                    width: 10,
+                }],
+                webp_sources: Vec::new(),
+            },
+        );
+        let arena = Arena::new();
+        let root = parse_document(
+            &arena,
+            "> [!NOTE]\n> $x^2$ ![a](/images/a.png)\n\n```dot\ndigraph { a -> b }\n```\n\n<b>raw</b>\n",
+            &options,
+        );
+        render_diagrams(&arena, root, 0, "/").unwrap();
+        render_math(&arena, root, 0).unwrap();
+        render_admonitions(&arena, root, &AdmonitionsConfig::default());
+        replace_images(&arena, root, &images, "/");
+
+        let html = render_html(root, &options, &Plugins::default()).unwrap();
+        for markup in ["<aside", "</aside>", "<math", "srcset=", "<svg"] {
+            assert!(html.contains(markup), "{} missing from {}", markup, html);
+        }
+        // Html written in the markdown is still left out
+        assert!(!html.contains("<b>raw</b>"));
+    }
+
+    #[test]
+    fn reading_time_rounds_up_to_a_minute() {
+        assert_eq!(reading_time(0, 200), 1);
+        assert_eq!(reading_time(201, 200), 2);
+        assert_eq!(reading_time(10, 0), 10);
+    }
+
+    #[test]
+    fn build_toc_keeps_headings_that_skip_levels() {
+        let toc = build_toc(vec![heading(3, "Child"), heading(1, "Top")], 6);
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].title, "Child");
+        assert_eq!(toc[1].title, "Top");
+    }
+
+    fn page(draft: bool, date: Option<i64>, expires: Option<i64>) -> Page {
+        let days_from_now = |days: i64| (Utc::now() + chrono::Duration::days(days)).fixed_offset();
+        Page {
+            metadata: FrontMatter {
+                draft: Some(draft),
+                ..Default::default()
+            },
+            slug: "post".to_string(),
+            content: String::new(),
+            line_offset: 0,
+            date: date.map(days_from_now),
+            updated: None,
+            expires: expires.map(days_from_now),
+        }
+    }
+
+    #[test]
+    fn is_published_leaves_out_drafts_unless_asked() {
+        assert!(is_published(&page(false, Some(-1), None), false, false));
+        assert!(!is_published(&page(true, Some(-1), None), false, false));
+        assert!(is_published(&page(true, Some(-1), None), true, false));
+    }
+
+    #[test]
+    fn is_published_leaves_out_future_pages_unless_asked() {
+        assert!(!is_published(&page(false, Some(1), None), false, false));
+        assert!(is_published(&page(false, Some(1), None), false, true));
+        assert!(!is_published(&page(true, Some(1), None), false, true));
+        assert!(is_published(&page(false, None, None), false, false));
+    }
+
+    #[test]
+    fn is_published_always_leaves_out_expired_pages() {
+        assert!(is_published(&page(false, Some(-2), Some(1)), false, false));
+        assert!(!is_published(
+            &page(false, Some(-2), Some(-1)),
+            false,
+            false
+        ));
+        assert!(!is_published(&page(true, Some(1), Some(-1)), true, true));
+    }
+
+    #[test]
+    fn claim_output_path_rejects_colliding_slugs() {
+        let mut state = BuildState::default();
+        let output_path = Path::new("public/blog/hello-world.html");
+        state
+            .claim_output_path(output_path, Path::new("content/blog/hello.md"))
+            .unwrap();
+        let error = state
+            .claim_output_path(output_path, Path::new("content/blog/hello-again.md"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("content/blog/hello.md"), "{}", error);
+        assert!(error.contains("content/blog/hello-again.md"), "{}", error);
+    }
+
+    #[test]
+    fn minify_page_keeps_whitespace_in_pre() {
+        let html = "<html><head></head><body>\n  <p>a   b</p>\n  \
+                    <pre><code>fn main() {\n    let  x = 1;\n}\n</code></pre>\n</body></html>";
+        let minified = minify_page(html.to_string(), true);
+        assert!(minified.contains("<pre><code>fn main() {\n    let  x = 1;\n}\n</code></pre>"));
+        assert!(!minified.contains("<p>a   b</p>"));
+        assert_eq!(minify_page(html.to_string(), false), html);
+    }
+}
@@ -218,7 +218,7 @@ fn collect_project_config(project_name: String) -> Result<Config> {
 
     let description = Input::<String>::new()
         .with_prompt("Project description")
-        .default(format!("{}", project_name))
+        .default(project_name.to_string())
         .interact_text()
         .context("Failed to get project description")?;
 
@@ -233,6 +233,7 @@ fn collect_project_config(project_name: String) -> Result<Config> {
         content_dir,
         description,
         author,
+        ..Default::default()
     })
 }
 
@@ -265,8 +266,6 @@ fn get_absolute_path<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
 }
 
 fn generate_timestamp(time: &SystemTime) -> Result<String> {
-    let timestamp = print_system_time_to_rfc3339(time)
-        .replace(':', "-")
-        .replace('.', "-");
+    let timestamp = print_system_time_to_rfc3339(time).replace([':', '.'], "-");
     Ok(timestamp)
 }
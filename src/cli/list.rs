@@ -70,14 +70,13 @@ fn build_tree(entries: &[PathBuf], root: &Path) -> Vec<TreeNode> {
     for entry in sorted_entries {
         let relative_path = entry.strip_prefix(root).unwrap_or(entry);
 
-        if let Some(parent_path) = relative_path.parent() {
-            if parent_path != Path::new("") && tree_map.contains_key(&parent_path.to_path_buf()) {
-                if let Some(child_node) = tree_map.remove(&relative_path.to_path_buf()) {
-                    if let Some(parent_node) = tree_map.get_mut(&parent_path.to_path_buf()) {
-                        parent_node.children.push(child_node);
-                    }
-                }
-            }
+        if let Some(parent_path) = relative_path.parent()
+            && parent_path != Path::new("")
+            && tree_map.contains_key(&parent_path.to_path_buf())
+            && let Some(child_node) = tree_map.remove(&relative_path.to_path_buf())
+            && let Some(parent_node) = tree_map.get_mut(&parent_path.to_path_buf())
+        {
+            parent_node.children.push(child_node);
         }
     }
 
@@ -89,7 +88,7 @@ fn build_tree(entries: &[PathBuf], root: &Path) -> Vec<TreeNode> {
     root_children
 }
 
-fn sort_children(children: &mut Vec<TreeNode>) {
+fn sort_children(children: &mut [TreeNode]) {
     children.sort_by(|a, b| match (a.is_dir, b.is_dir) {
         (true, false) => std::cmp::Ordering::Less,
         (false, true) => std::cmp::Ordering::Greater,
@@ -97,7 +96,7 @@ fn sort_children(children: &mut Vec<TreeNode>) {
     });
 }
 
-fn sort_all_children(nodes: &mut Vec<TreeNode>) {
+fn sort_all_children(nodes: &mut [TreeNode]) {
     for node in nodes.iter_mut() {
         sort_children(&mut node.children);
         sort_all_children(&mut node.children);
@@ -117,7 +116,7 @@ fn print_tree(nodes: &[TreeNode], prefix: &str, is_root: bool) {
 
         if !node.children.is_empty() {
             let new_prefix = if is_root && nodes.len() == 1 {
-                format!("{}", prefix)
+                prefix.to_string()
             } else {
                 let extension = if is_last { "    " } else { "│   " };
                 format!("{}{}", prefix, extension)
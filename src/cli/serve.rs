@@ -1,15 +1,31 @@
-use std::{net::SocketAddr, path::Path};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
-use axum::{http::StatusCode, routing::get, Router};
+use axum::{
+    Router,
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    routing::get,
+};
+use tower::util::ServiceExt;
 use tower_http::{
-    services::ServeFile, trace::TraceLayer
+    services::{ServeDir, ServeFile},
+    trace::TraceLayer,
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use walkdir::WalkDir;
-use tower::util::ServiceExt;
 
-pub async fn serve_content(port: u16, open: bool) -> Result<()> {
+use crate::{
+    assets::stylesheets_state,
+    cli::build::{BuildOptions, rebuild_stylesheets},
+};
+
+pub async fn serve_content(port: u16, open: bool, options: BuildOptions) -> Result<()> {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
@@ -19,15 +35,19 @@ pub async fn serve_content(port: u16, open: bool) -> Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    tokio::spawn(watch_stylesheets(
+        Path::new("static"),
+        Path::new("public"),
+        options,
+    ));
+
     let app = create_static_server("public")?;
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
     
     println!("Server running at http://localhost:{}", port);
-    
-    if open {
-        if let Err(e) = open_browser(&format!("http://localhost:{}", port)) {
-            tracing::warn!("Failed to open browser: {}", e);
-        }
+
+    if open && let Err(e) = open_browser(&format!("http://localhost:{}", port)) {
+        tracing::warn!("Failed to open browser: {}", e);
     }
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
@@ -54,14 +74,10 @@ fn create_static_server(directory: &str) -> Result<Router> {
             
             let route_path = format!("/{}", relative_path.to_string_lossy().replace('\\', "/"));
             let serve_path = file_path.to_path_buf();
-            router = router.route(&route_path, get(move || async move {
-                ServeFile::new(&serve_path).oneshot(
-                    axum::http::Request::builder()
-                        .uri("/")
-                        .body(axum::body::Body::empty())
-                        .unwrap()
-                ).await
-            }));
+            router = router.route(
+                &route_path,
+                get(move |request: Request| serve_file(serve_path.clone(), request)),
+            );
             
             route_count += 1;
             tracing::debug!("Added route: {} -> {:?}", route_path, file_path);
@@ -82,56 +98,61 @@ fn create_static_server(directory: &str) -> Result<Router> {
             }
         }
     }
-    
+
     for (dir_route, index_path) in directory_routes {
-        let serve_path = index_path.clone();
+        // Redirect to the trailing slash so relative links in the page,
+        // like the files of a page bundle, resolve against its directory
+        let dir_route_with_slash = format!("{}/", dir_route);
+        let redirect_target = dir_route_with_slash.clone();
         router = router.route(&dir_route, get(move || async move {
-            ServeFile::new(&serve_path).oneshot(
-                axum::http::Request::builder()
-                    .uri("/")
-                    .body(axum::body::Body::empty())
-                    .unwrap()
-            ).await
+            Redirect::permanent(&redirect_target)
         }));
         tracing::info!("Added directory route: {} -> {:?}", dir_route, index_path);
         route_count += 1;
         
-        let dir_route_with_slash = format!("{}/", dir_route);
         let serve_path_with_slash = index_path.clone();
-        router = router.route(&dir_route_with_slash, get(move || async move {
-            ServeFile::new(&serve_path_with_slash).oneshot(
-                axum::http::Request::builder()
-                    .uri("/")
-                    .body(axum::body::Body::empty())
-                    .unwrap()
-            ).await
-        }));
+        router = router.route(
+            &dir_route_with_slash,
+            get(move |request: Request| serve_file(serve_path_with_slash.clone(), request)),
+        );
         route_count += 1;
     }
     
     let index_path = Path::new(directory).join("index.html");
     if index_path.exists() {
         let index_serve_path = index_path.clone();
-        router = router.route("/", get(move || async move {
-            ServeFile::new(&index_serve_path).oneshot(
-                axum::http::Request::builder()
-                    .uri("/")
-                    .body(axum::body::Body::empty())
-                    .unwrap()
-            ).await
-        }));
+        router = router.route(
+            "/",
+            get(move |request: Request| serve_file(index_serve_path.clone(), request)),
+        );
         tracing::info!("Added root route serving index.html");
     }
     
     println!("📋 Created {} file routes", route_count);
     
-    router = router.fallback(|| async {
-        (StatusCode::NOT_FOUND, "File not found")
-    });
-    
+    // Files written after the server started, like a stylesheet with a new
+    // fingerprint, have no route of their own
+    let not_found = get(|| async { (StatusCode::NOT_FOUND, "File not found") });
+    router = router.fallback_service(
+        ServeDir::new(directory)
+            .precompressed_br()
+            .precompressed_gzip()
+            .not_found_service(not_found),
+    );
+
     Ok(router.layer(TraceLayer::new_for_http()))
 }
 
+/// Serves a file, or the `.br`/`.gz` sibling written by the build when the
+/// request's `Accept-Encoding` allows it.
+async fn serve_file(path: PathBuf, request: Request) -> impl IntoResponse {
+    ServeFile::new(path)
+        .precompressed_br()
+        .precompressed_gzip()
+        .oneshot(request)
+        .await
+}
+
 fn open_browser(url: &str) -> Result<()> {
     #[cfg(target_os = "windows")]
     {
@@ -156,3 +177,28 @@ fn open_browser(url: &str) -> Result<()> {
     
     Ok(())
 }
+
+/// Polls the scss sources and recompiles the stylesheets whenever one of
+/// them, or one of their partials, changes, using the options `serve` was
+/// started with.
+async fn watch_stylesheets(
+    static_dir: &'static Path,
+    output_dir: &'static Path,
+    options: BuildOptions,
+) {
+    let mut last_state = stylesheets_state(static_dir);
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+        let state = stylesheets_state(static_dir);
+        if state == last_state {
+            continue;
+        }
+        last_state = state;
+
+        if let Err(e) = rebuild_stylesheets(options, output_dir).await {
+            eprintln!("Failed to recompile stylesheets: {:#}", e);
+        }
+    }
+}
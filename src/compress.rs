@@ -0,0 +1,211 @@
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use flate2::{Compression, write::GzEncoder};
+use tokio::fs::{metadata, read, read_to_string, remove_file, write};
+use walkdir::WalkDir;
+
+use crate::{
+    consts::{CACHE_DIR, CompressConfig},
+    utils::write_if_changed,
+};
+
+const COMPRESSIBLE_EXTENSIONS: [&str; 9] = [
+    "html", "css", "js", "json", "xml", "svg", "txt", "map", "wasm",
+];
+
+/// The suffixes of the precompressed siblings `precompress` can write.
+const SIBLING_SUFFIXES: [&str; 2] = [".gz", ".br"];
+
+type Compressor = fn(&[u8]) -> Result<Vec<u8>>;
+
+fn is_compressible(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSIBLE_EXTENSIONS.contains(&ext))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Returns the file a `.gz` or `.br` sibling was compressed from, with the
+/// suffix that was added to it.
+fn sibling_source(path: &Path) -> Option<(PathBuf, &'static str)> {
+    let name = path.file_name()?.to_str()?;
+    SIBLING_SUFFIXES.iter().find_map(|suffix| {
+        let source = path.with_file_name(name.strip_suffix(suffix)?);
+        is_compressible(&source).then_some((source, *suffix))
+    })
+}
+
+/// Where `precompress` records the siblings it wrote, so it only ever
+/// removes its own and never the `.gz` files a site ships in `static/`.
+fn written_list_path() -> PathBuf {
+    Path::new(CACHE_DIR).join("precompressed.txt")
+}
+
+/// Reads the siblings earlier builds wrote. Output from before the list was
+/// kept is adopted, apart from files that come from `static/`.
+async fn read_written(path: &Path, output_dir: &Path) -> BTreeSet<PathBuf> {
+    if let Ok(list) = read_to_string(path).await {
+        return list.lines().map(PathBuf::from).collect();
+    }
+    WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.into_path())
+        .filter(|sibling| sibling_source(sibling).is_some())
+        .filter(|sibling| {
+            sibling
+                .strip_prefix(output_dir)
+                .is_ok_and(|relative| !Path::new("static").join(relative).exists())
+        })
+        .collect()
+}
+
+/// Removes the siblings `precompress` wrote that no longer match the config:
+/// those of an encoding that is turned off, of a file below `min_size` and of
+/// a file that isn't there anymore.
+async fn prune_siblings(
+    output_dir: &Path,
+    config: &CompressConfig,
+    written: &mut BTreeSet<PathBuf>,
+) -> Result<()> {
+    let siblings = written
+        .iter()
+        .filter(|sibling| sibling.starts_with(output_dir))
+        .cloned()
+        .collect::<Vec<PathBuf>>();
+    for sibling in siblings {
+        let Some((source, suffix)) = sibling_source(&sibling) else {
+            continue;
+        };
+        let enabled = match suffix {
+            ".gz" => config.gzip,
+            _ => config.brotli,
+        };
+        let wanted = enabled
+            && metadata(&source)
+                .await
+                .is_ok_and(|source| source.is_file() && source.len() >= config.min_size);
+        if wanted {
+            continue;
+        }
+        match remove_file(&sibling).await {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                return Err(error)
+                    .with_context(|| format!("Failed to remove {}", sibling.display()));
+            }
+            _ => {}
+        }
+        written.remove(&sibling);
+    }
+    Ok(())
+}
+
+fn gzip(content: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(content)?;
+    Ok(encoder.finish()?)
+}
+
+fn brotli(content: &[u8]) -> Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+        encoder.write_all(content)?;
+    }
+    Ok(compressed)
+}
+
+/// Writes `.gz` and `.br` siblings for the text files in the output dir, as
+/// picked up by nginx `gzip_static`/`brotli_static` and `grimoire serve`.
+/// Siblings that are newer than their file are kept, as are `.gz` and `.br`
+/// files the site ships itself. Siblings written by an earlier build that the
+/// config no longer asks for are removed. Returns how many files were
+/// compressed.
+pub async fn precompress(output_dir: &Path, config: &CompressConfig) -> Result<usize> {
+    let list_path = written_list_path();
+    let mut written = read_written(&list_path, output_dir).await;
+    prune_siblings(output_dir, config, &mut written).await?;
+
+    let mut encodings: Vec<(&str, Compressor)> = Vec::new();
+    if config.gzip {
+        encodings.push((".gz", gzip));
+    }
+    if config.brotli {
+        encodings.push((".br", brotli));
+    }
+
+    let mut compressed = 0;
+    for entry in WalkDir::new(output_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && is_compressible(e.path()))
+    {
+        let source = entry.metadata()?;
+        if source.len() < config.min_size {
+            continue;
+        }
+
+        let mut content = None;
+        for (suffix, compress) in &encodings {
+            let output_path = with_suffix(entry.path(), suffix);
+            if let Ok(existing) = metadata(&output_path).await
+                && (!written.contains(&output_path) || existing.modified()? >= source.modified()?)
+            {
+                continue;
+            }
+
+            if content.is_none() {
+                content = Some(
+                    read(entry.path())
+                        .await
+                        .with_context(|| format!("Failed to read {}", entry.path().display()))?,
+                );
+            }
+            let bytes = compress(content.as_deref().unwrap_or_default())
+                .with_context(|| format!("Failed to compress {}", entry.path().display()))?;
+            write(&output_path, bytes)
+                .await
+                .with_context(|| format!("Failed to write {}", output_path.display()))?;
+            written.insert(output_path);
+        }
+        if content.is_some() {
+            compressed += 1;
+        }
+    }
+
+    let list = written
+        .iter()
+        .map(|sibling| format!("{}\n", sibling.display()))
+        .collect::<String>();
+    write_if_changed(&list_path, list).await?;
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sibling_source_strips_the_encoding_suffix() {
+        assert_eq!(
+            sibling_source(Path::new("public/style.css.br")),
+            Some((PathBuf::from("public/style.css"), ".br"))
+        );
+        assert_eq!(
+            sibling_source(Path::new("public/index.html.gz")),
+            Some((PathBuf::from("public/index.html"), ".gz"))
+        );
+        assert_eq!(sibling_source(Path::new("public/archive.tar.gz")), None);
+        assert_eq!(sibling_source(Path::new("public/style.css")), None);
+    }
+}
@@ -1,25 +1,478 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-pub const GRIMOIRE_CONFIG_NAME: &'static str = "grimoire.config.json";
+pub const GRIMOIRE_CONFIG_NAME: &str = "grimoire.config.json";
+pub const BUNDLE_INDEX: &str = "index.md";
+/// Holds build artifacts that are expensive to recreate, like resized images.
+pub const CACHE_DIR: &str = ".grimoire-cache";
 
-#[derive(Serialize, Debug, Clone, Deserialize)]
+#[derive(Serialize, Debug, Clone, Deserialize, Default)]
 pub struct Config {
     pub project: String,
     pub content_dir: String,
     pub description: String,
     pub author: String,
+    #[serde(default)]
+    pub toc: TocConfig,
+    #[serde(default)]
+    pub summary: SummaryConfig,
+    #[serde(default)]
+    pub date: DateConfig,
+    #[serde(default)]
+    pub permalinks: HashMap<String, String>,
+    #[serde(default)]
+    pub redirects: RedirectConfig,
+    #[serde(default)]
+    pub assets: AssetsConfig,
+    #[serde(default)]
+    pub images: ImagesConfig,
+    #[serde(default)]
+    pub html: HtmlConfig,
+    #[serde(default)]
+    pub compress: CompressConfig,
+    #[serde(default)]
+    pub highlight: HighlightConfig,
+    #[serde(default)]
+    pub markdown: MarkdownConfig,
+    #[serde(default)]
+    pub sanitize: SanitizeConfig,
+    #[serde(default)]
+    pub admonitions: AdmonitionsConfig,
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TocConfig {
+    pub enabled: bool,
+    pub depth: u8,
+}
+
+impl Default for TocConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            depth: 3,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SummaryConfig {
+    pub words: usize,
+    pub words_per_minute: usize,
+}
+
+impl Default for SummaryConfig {
+    fn default() -> Self {
+        Self {
+            words: 50,
+            words_per_minute: 200,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DateConfig {
+    pub timezone: String,
+    pub format: String,
+    pub locale: String,
+}
+
+impl Default for DateConfig {
+    fn default() -> Self {
+        Self {
+            timezone: "UTC".to_string(),
+            format: "%Y-%m-%d".to_string(),
+            locale: "en_US".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct RedirectConfig {
+    pub netlify: bool,
+    pub nginx: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct AssetsConfig {
+    /// Glob patterns, relative to `static/`, of files that are not copied.
+    pub ignore: Vec<String>,
+    /// Output file name mapped to the glob patterns of the files it bundles.
+    pub bundles: BTreeMap<String, Vec<String>>,
+    /// Overrides whether bundles are minified, which otherwise follows the
+    /// build profile.
+    pub minify: Option<bool>,
+    /// Writes css and js files under content hashed names, listed in
+    /// `asset-manifest.json`, so they can be cached forever.
+    pub fingerprint: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ImagesConfig {
+    /// Off by default, as it re-encodes every image and changes the markup
+    /// of the pages that show them.
+    pub enabled: bool,
+    /// Widths, in pixels, of the resized variants. Widths at or above the
+    /// width of an image are skipped for it.
+    pub widths: Vec<u32>,
+    pub webp: bool,
+    /// JPEG quality of resized variants, from 1 to 100.
+    pub quality: u8,
+    /// `sizes` attribute of the generated `srcset` markup.
+    pub sizes: String,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            widths: vec![480, 960, 1600],
+            webp: true,
+            quality: 80,
+            sizes: "100vw".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct HtmlConfig {
+    /// Minifies rendered pages, keeping `<pre>` blocks and inline scripts
+    /// as they are. Also enabled by `grimoire build --minify`.
+    pub minify: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressConfig {
+    pub gzip: bool,
+    pub brotli: bool,
+    /// Files smaller than this many bytes aren't worth compressing.
+    pub min_size: u64,
+}
+
+impl Default for CompressConfig {
+    fn default() -> Self {
+        Self {
+            gzip: false,
+            brotli: false,
+            min_size: 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HighlightConfig {
+    /// Name of a theme bundled with syntect, or the file stem of a
+    /// `.tmTheme` file in `themes/`.
+    pub light_theme: String,
+    pub dark_theme: String,
+    pub mode: ThemeMode,
+}
+
+impl Default for HighlightConfig {
+    fn default() -> Self {
+        Self {
+            light_theme: "base16-ocean.light".to_string(),
+            dark_theme: "base16-mocha.dark".to_string(),
+            mode: ThemeMode::default(),
+        }
+    }
+}
+
+/// Markdown extensions and render options, which pages can override with
+/// a `markdown` map in their front matter.
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MarkdownConfig {
+    /// Curly quotes, dashes and ellipses.
+    pub smart: bool,
+    /// Renders soft line breaks as `<br />`.
+    pub hardbreaks: bool,
+    /// Passes raw html and dangerous links through.
+    pub unsafe_html: bool,
+    /// Column to wrap rendered text at, `0` to keep lines as written.
+    pub width: usize,
+    pub strikethrough: bool,
+    pub tagfilter: bool,
+    pub table: bool,
+    pub autolink: bool,
+    pub tasklist: bool,
+    pub superscript: bool,
+    pub footnotes: bool,
+    pub description_lists: bool,
+    /// Renders `$…$` and `$$…$$` into MathML at build time.
+    pub math: bool,
 }
 
-#[derive(Deserialize, Debug)]
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        Self {
+            smart: true,
+            hardbreaks: false,
+            unsafe_html: true,
+            width: 80,
+            strikethrough: true,
+            tagfilter: true,
+            table: true,
+            autolink: true,
+            tasklist: true,
+            superscript: true,
+            footnotes: true,
+            description_lists: true,
+            math: false,
+        }
+    }
+}
+
+impl MarkdownConfig {
+    pub fn with_overrides(&self, overrides: &MarkdownOverrides) -> Self {
+        Self {
+            smart: overrides.smart.unwrap_or(self.smart),
+            hardbreaks: overrides.hardbreaks.unwrap_or(self.hardbreaks),
+            unsafe_html: overrides.unsafe_html.unwrap_or(self.unsafe_html),
+            width: overrides.width.unwrap_or(self.width),
+            strikethrough: overrides.strikethrough.unwrap_or(self.strikethrough),
+            tagfilter: overrides.tagfilter.unwrap_or(self.tagfilter),
+            table: overrides.table.unwrap_or(self.table),
+            autolink: overrides.autolink.unwrap_or(self.autolink),
+            tasklist: overrides.tasklist.unwrap_or(self.tasklist),
+            superscript: overrides.superscript.unwrap_or(self.superscript),
+            footnotes: overrides.footnotes.unwrap_or(self.footnotes),
+            description_lists: overrides
+                .description_lists
+                .unwrap_or(self.description_lists),
+            math: overrides.math.unwrap_or(self.math),
+        }
+    }
+}
+
+/// Per page overrides of `MarkdownConfig`, e.g. `markdown: { hardbreaks: true }`.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MarkdownOverrides {
+    pub smart: Option<bool>,
+    pub hardbreaks: Option<bool>,
+    pub unsafe_html: Option<bool>,
+    pub width: Option<usize>,
+    pub strikethrough: Option<bool>,
+    pub tagfilter: Option<bool>,
+    pub table: Option<bool>,
+    pub autolink: Option<bool>,
+    pub tasklist: Option<bool>,
+    pub superscript: Option<bool>,
+    pub footnotes: Option<bool>,
+    pub description_lists: Option<bool>,
+    pub math: Option<bool>,
+}
+
+/// Which sections have their rendered html sanitised, along with what is
+/// allowed through on top of ammonia's defaults and grimoire's own markup.
+#[derive(Serialize, Debug, Clone, Deserialize, Default)]
+#[serde(default)]
+pub struct SanitizeConfig {
+    /// Trust level of sections without an entry in `sections`.
+    pub trust: TrustLevel,
+    /// Trust levels keyed by section, e.g. `"community": "untrusted"`.
+    pub sections: HashMap<String, TrustLevel>,
+    pub tags: Vec<String>,
+    /// Attributes allowed per tag, with `*` for every tag.
+    pub attributes: HashMap<String, Vec<String>>,
+}
+
+impl SanitizeConfig {
+    pub fn trust(&self, section: &str) -> TrustLevel {
+        self.sections.get(section).copied().unwrap_or(self.trust)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrustLevel {
+    /// Raw html is rendered as written.
+    #[default]
+    Trusted,
+    /// Rendered html is sanitised against the allow-list.
+    Untrusted,
+}
+
+/// Kinds of `> [!NOTE]` and `:::note` admonitions, keyed by lowercase name
+/// with their default title. Setting `kinds` replaces the GitHub kinds.
+#[derive(Serialize, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdmonitionsConfig {
+    pub kinds: BTreeMap<String, String>,
+}
+
+impl Default for AdmonitionsConfig {
+    fn default() -> Self {
+        Self {
+            kinds: [
+                ("note", "Note"),
+                ("tip", "Tip"),
+                ("important", "Important"),
+                ("warning", "Warning"),
+                ("caution", "Caution"),
+            ]
+            .into_iter()
+            .map(|(kind, title)| (kind.to_string(), title.to_string()))
+            .collect(),
+        }
+    }
+}
+
+impl AdmonitionsConfig {
+    pub fn title(&self, kind: &str) -> Option<&str> {
+        self.kinds.get(&kind.to_lowercase()).map(String::as_str)
+    }
+}
+
+/// How the light or dark syntax theme is picked.
+#[derive(Serialize, Debug, Clone, Copy, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemeMode {
+    /// Follows `prefers-color-scheme`.
+    #[default]
+    Media,
+    /// Follows a `dark` or `light` class on `<html>`, falling back to
+    /// `prefers-color-scheme` without one.
+    Class,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+pub enum BuildProfile {
+    #[default]
+    Development,
+    Production,
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub struct FrontMatter {
     pub title: String,
     pub author: Option<String>,
     pub date: Option<String>,
-    pub tags: Option<String>,
+    pub updated: Option<String>,
+    pub expires: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_terms")]
+    pub tags: Option<Vec<String>>,
     pub description: Option<String>,
-    pub slug: String,
+    pub slug: Option<String>,
+    pub aliases: Option<Vec<String>>,
     pub draft: Option<bool>,
+    pub toc: Option<bool>,
+    pub toc_depth: Option<u8>,
+    pub markdown: Option<MarkdownOverrides>,
     pub extra: Option<HashMap<String, serde_json::Value>>,
 }
+
+/// A single term, which YAML reads as a number or a bool when it looks
+/// like one, as in `tags: [2024, rust]`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTerm {
+    Text(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<RawTerm> for String {
+    fn from(term: RawTerm) -> Self {
+        match term {
+            RawTerm::Text(term) => term,
+            RawTerm::Integer(term) => term.to_string(),
+            RawTerm::Float(term) => term.to_string(),
+            RawTerm::Bool(term) => term.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTerms {
+    List(Vec<RawTerm>),
+    Comma(String),
+    Scalar(RawTerm),
+}
+
+/// Accepts taxonomy terms either as a YAML list (`tags: [rust, async]`) or
+/// as a comma separated string (`tags: rust, async`).
+fn deserialize_terms<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    let terms = match Option::<RawTerms>::deserialize(deserializer)? {
+        Some(RawTerms::List(terms)) => terms.into_iter().map(String::from).collect(),
+        Some(RawTerms::Comma(terms)) => terms.split(',').map(String::from).collect(),
+        Some(RawTerms::Scalar(term)) => vec![String::from(term)],
+        None => return Ok(None),
+    };
+
+    Ok(Some(normalize_terms(terms)))
+}
+
+/// Lowercases terms and collapses their whitespace, dropping empty and
+/// duplicate terms while keeping the original order.
+fn normalize_terms(terms: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for term in terms {
+        let term = term
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+            .to_lowercase();
+        if !term.is_empty() && !normalized.contains(&term) {
+            normalized.push(term);
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gray_matter::{Matter, engine::YAML};
+
+    fn tags(front_matter: &str) -> Option<Vec<String>> {
+        Matter::<YAML>::new()
+            .parse::<FrontMatter>(&format!("---\ntitle: Post\n{}\n---\n", front_matter))
+            .unwrap()
+            .data
+            .unwrap()
+            .tags
+    }
+
+    #[test]
+    fn terms_are_normalized() {
+        assert_eq!(
+            tags("tags: [Rust,  Async  Code, rust, '']"),
+            Some(vec!["rust".to_string(), "async code".to_string()])
+        );
+        assert_eq!(
+            tags("tags: Rust, web"),
+            Some(vec!["rust".to_string(), "web".to_string()])
+        );
+        assert_eq!(tags("author: me"), None);
+    }
+
+    #[test]
+    fn numeric_and_bool_terms_are_stringified() {
+        assert_eq!(
+            tags("tags: [2024, rust, 1.5, true]"),
+            Some(vec![
+                "2024".to_string(),
+                "rust".to_string(),
+                "1.5".to_string(),
+                "true".to_string()
+            ])
+        );
+        assert_eq!(tags("tags: 2024"), Some(vec!["2024".to_string()]));
+    }
+}
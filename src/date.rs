@@ -0,0 +1,142 @@
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, FixedOffset, Locale, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use tera::{Filter, Value};
+
+use crate::consts::DateConfig;
+
+const DATETIME_FORMATS: [&str; 4] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+pub fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse::<Tz>()
+        .map_err(|e| anyhow!("Invalid timezone '{}': {}", name, e))
+}
+
+pub fn parse_locale(name: &str) -> Result<Locale> {
+    Locale::try_from(name).map_err(|_| anyhow!("Invalid locale: {}", name))
+}
+
+/// Parses a front matter date. RFC 3339 values keep their own offset, while
+/// datetimes without an offset and plain `YYYY-MM-DD` dates are taken to be
+/// in the site timezone.
+pub fn parse_date(input: &str, timezone: Tz) -> Result<DateTime<FixedOffset>> {
+    let input = input.trim();
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(input) {
+        return Ok(date);
+    }
+
+    for format in DATETIME_FORMATS {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(input, format) {
+            return localize(naive, timezone, input);
+        }
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return localize(date.and_time(NaiveTime::MIN), timezone, input);
+    }
+
+    bail!(
+        "Invalid date '{}': expected RFC 3339, an ISO 8601 datetime or YYYY-MM-DD",
+        input
+    )
+}
+
+fn localize(naive: NaiveDateTime, timezone: Tz, input: &str) -> Result<DateTime<FixedOffset>> {
+    timezone
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|date| date.fixed_offset())
+        .with_context(|| format!("Date '{}' does not exist in timezone {}", input, timezone))
+}
+
+/// Tera `date` filter which defaults to the format, timezone and locale from
+/// the site config. Each of them can be overridden per call, e.g.
+/// `{{ date | date(format="%A %-d %B %Y", locale="fr_FR") }}`.
+pub struct DateFilter {
+    format: String,
+    timezone: Tz,
+    locale: Locale,
+}
+
+impl DateFilter {
+    pub fn new(config: &DateConfig) -> Result<Self> {
+        Ok(Self {
+            format: config.format.clone(),
+            timezone: parse_timezone(&config.timezone)?,
+            locale: parse_locale(&config.locale)?,
+        })
+    }
+}
+
+impl Filter for DateFilter {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let input = value
+            .as_str()
+            .ok_or_else(|| tera::Error::msg("Filter `date` expects a date string"))?;
+
+        let timezone = match args.get("timezone").and_then(Value::as_str) {
+            Some(name) => parse_timezone(name).map_err(|e| tera::Error::msg(e.to_string()))?,
+            None => self.timezone,
+        };
+        let locale = match args.get("locale").and_then(Value::as_str) {
+            Some(name) => parse_locale(name).map_err(|e| tera::Error::msg(e.to_string()))?,
+            None => self.locale,
+        };
+        let format = args
+            .get("format")
+            .and_then(Value::as_str)
+            .unwrap_or(&self.format);
+
+        let date = parse_date(input, timezone)
+            .map_err(|e| tera::Error::msg(e.to_string()))?
+            .with_timezone(&timezone);
+
+        let mut formatted = String::new();
+        write!(formatted, "{}", date.format_localized(format, locale))
+            .map_err(|_| tera::Error::msg(format!("Invalid date format: {}", format)))?;
+
+        Ok(Value::String(formatted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_keeps_rfc3339_offsets() {
+        let date = parse_date("2025-03-01T10:00:00+02:00", chrono_tz::UTC).unwrap();
+        assert_eq!(date.to_rfc3339(), "2025-03-01T10:00:00+02:00");
+    }
+
+    #[test]
+    fn parse_date_puts_local_dates_in_the_site_timezone() {
+        let timezone = parse_timezone("Europe/Paris").unwrap();
+        assert_eq!(
+            parse_date("2025-07-14", timezone).unwrap().to_rfc3339(),
+            "2025-07-14T00:00:00+02:00"
+        );
+        assert_eq!(
+            parse_date(" 2025-01-14 08:30 ", timezone)
+                .unwrap()
+                .to_rfc3339(),
+            "2025-01-14T08:30:00+01:00"
+        );
+    }
+
+    #[test]
+    fn parse_date_rejects_missing_and_invalid_times() {
+        let timezone = parse_timezone("Europe/Paris").unwrap();
+        // Clocks skip from 02:00 to 03:00 that night
+        assert!(parse_date("2025-03-30T02:30", timezone).is_err());
+        assert!(parse_date("14/07/2025", timezone).is_err());
+    }
+}
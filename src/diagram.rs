@@ -0,0 +1,185 @@
+use std::{
+    cell::Cell,
+    panic::{AssertUnwindSafe, catch_unwind, set_hook, take_hook},
+    sync::Arc,
+};
+
+use anyhow::{Result, anyhow};
+use layout::{
+    backends::svg::SVGWriter,
+    gv::{DotParser, GraphBuilder},
+};
+use sha2::{Digest, Sha256};
+
+/// Code fence languages that are rendered as diagrams.
+const DIAGRAM_LANGUAGES: [&str; 2] = ["dot", "graphviz"];
+
+const FONT_FAMILY: &str = "Times, serif";
+
+thread_local! {
+    /// Set while a layout runs, so its panics aren't printed.
+    static IN_LAYOUT: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn is_diagram(language: &str) -> bool {
+    DIAGRAM_LANGUAGES.contains(&language)
+}
+
+/// The id prefix of the `index`th diagram on the page at `url`. It is keyed
+/// on the page and the position so identical diagrams, or diagrams from
+/// several posts shown on one listing, never share ids.
+pub fn diagram_id(url: &str, index: usize) -> String {
+    let key = Sha256::digest(url)[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("diagram-{}-{}", key, index)
+}
+
+/// Lays out a Graphviz graph and renders it into an inline svg figure whose
+/// ids start with `id`.
+pub fn render_diagram(source: &str, id: &str) -> Result<String> {
+    let mut parser = DotParser::new(source);
+    let graph = parser
+        .process()
+        .map_err(|e| anyhow!("Invalid dot diagram: {}", e))?;
+
+    // The layout code asserts on graphs it can't handle rather than failing
+    let svg = catch_layout_panic(|| {
+        let mut builder = GraphBuilder::new();
+        builder.visit_graph(&graph);
+        let mut visual_graph = builder.get();
+        let mut svg = SVGWriter::new();
+        visual_graph.do_it(false, false, false, &mut svg);
+        svg.finalize()
+    })
+    .map_err(|_| anyhow!("Failed to lay out dot diagram"))?;
+
+    Ok(format!(
+        "<figure class=\"diagram\">{}</figure>\n",
+        inline_svg(&svg, id)
+    ))
+}
+
+/// Runs a layout, turning its panics into an error without the panic hook
+/// printing them. The hook is only swapped for the duration of the layout,
+/// and panics on other threads still reach it meanwhile.
+fn catch_layout_panic<T>(layout: impl FnOnce() -> T) -> std::thread::Result<T> {
+    let previous = Arc::new(take_hook());
+    let hook = Arc::clone(&previous);
+    set_hook(Box::new(move |info| {
+        if !IN_LAYOUT.with(Cell::get) {
+            hook(info);
+        }
+    }));
+
+    IN_LAYOUT.with(|in_layout| in_layout.set(true));
+    let result = catch_unwind(AssertUnwindSafe(layout));
+    IN_LAYOUT.with(|in_layout| in_layout.set(false));
+
+    drop(take_hook());
+    match Arc::try_unwrap(previous) {
+        Ok(previous) => set_hook(previous),
+        // Another layout swapped the hook in the meantime and still holds it
+        Err(previous) => set_hook(Box::new(move |info| previous(info))),
+    }
+    result
+}
+
+/// Makes a standalone svg document fit for inlining: the xml declaration
+/// goes, ids are prefixed so diagrams on one page don't clash, and the font
+/// classes layout-rs emits, `a` followed by the font size, become attributes
+/// so the svg doesn't need a `<style>`.
+fn inline_svg(svg: &str, prefix: &str) -> String {
+    let svg = svg.get(svg.find("<svg").unwrap_or(0)..).unwrap_or(svg);
+    let svg = match (svg.find("<style>"), svg.find("</style>")) {
+        (Some(start), Some(end)) => format!("{}{}", &svg[..start], &svg[end + "</style>".len()..]),
+        _ => svg.to_string(),
+    };
+    let svg = svg
+        .replace("id=\"", &format!("id=\"{}-", prefix))
+        .replace("url(#", &format!("url(#{}-", prefix))
+        .replace("href=\"#", &format!("href=\"#{}-", prefix));
+
+    let mut inlined = String::with_capacity(svg.len());
+    let mut rest = svg.as_str();
+    while let Some(start) = rest.find("class=\"a") {
+        let class = &rest[start + "class=\"a".len()..];
+        let end = class.find('"').unwrap_or(class.len());
+        let size = &class[..end];
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_digit()) {
+            let kept = start + "class=\"a".len();
+            inlined.push_str(&rest[..kept]);
+            rest = &rest[kept..];
+            continue;
+        }
+
+        inlined.push_str(&rest[..start]);
+        inlined.push_str(&format!(
+            "font-size=\"{}\" font-family=\"{}\"",
+            size, FONT_FAMILY
+        ));
+        rest = class.get(end + 1..).unwrap_or("");
+    }
+    inlined.push_str(rest);
+    inlined
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn identical_diagrams_get_their_own_ids() {
+        let source = "digraph { a -> b }";
+        let first = render_diagram(source, &diagram_id("/blog/a.html", 0)).unwrap();
+        let second = render_diagram(source, &diagram_id("/blog/a.html", 1)).unwrap();
+        assert_ne!(first, second);
+        assert_ne!(diagram_id("/blog/a.html", 0), diagram_id("/blog/b.html", 0));
+    }
+
+    #[test]
+    fn catch_layout_panic_restores_the_panic_hook() {
+        static PANICS: AtomicUsize = AtomicUsize::new(0);
+        let previous = take_hook();
+        set_hook(Box::new(|_| {
+            PANICS.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        assert!(catch_layout_panic(|| panic!("layout")).is_err());
+        let _ = catch_unwind(|| panic!("elsewhere"));
+        let panics = PANICS.load(Ordering::SeqCst);
+
+        set_hook(previous);
+        assert_eq!(panics, 1);
+    }
+
+    #[test]
+    fn inline_svg_only_rewrites_font_classes() {
+        let svg = inline_svg(
+            "<svg><text class=\"a14\">x</text><g class=\"arrow\"/><g class=\"a\"/></svg>",
+            "diagram-x-0",
+        );
+        assert_eq!(
+            svg,
+            format!(
+                "<svg><text font-size=\"14\" font-family=\"{}\">x</text><g class=\"arrow\"/><g class=\"a\"/></svg>",
+                FONT_FAMILY
+            )
+        );
+    }
+
+    #[test]
+    fn inline_svg_prefixes_ids_and_references() {
+        let svg = inline_svg(
+            "<?xml version=\"1.0\"?><svg><marker id=\"arrow\"/><path marker-end=\"url(#arrow)\"/></svg>",
+            "diagram-x-0",
+        );
+        assert_eq!(
+            svg,
+            "<svg><marker id=\"diagram-x-0-arrow\"/><path marker-end=\"url(#diagram-x-0-arrow)\"/></svg>"
+        );
+    }
+}
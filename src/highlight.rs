@@ -0,0 +1,549 @@
+use std::{
+    collections::BTreeSet,
+    fs::{create_dir_all, read_to_string},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use sha2::{Digest, Sha256};
+use syntect::{
+    dumps::{dump_to_uncompressed_file, from_uncompressed_dump_file},
+    highlighting::{Color, Theme, ThemeSet},
+    html::{ClassStyle, css_for_theme_with_class_style},
+    parsing::{SyntaxDefinition, SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+use walkdir::WalkDir;
+
+use crate::consts::{CACHE_DIR, HighlightConfig, ThemeMode};
+
+/// Project level directory for custom `.tmTheme` files.
+pub const THEMES_DIR: &str = "themes";
+/// Project level directory for custom `.sublime-syntax` files.
+pub const SYNTAXES_DIR: &str = "syntaxes";
+
+/// Languages that are meant to be plain text and aren't worth a warning.
+const PLAIN_LANGUAGES: [&str; 4] = ["text", "plain", "plaintext", "txt"];
+
+static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+static WARNED_LANGUAGES: Mutex<BTreeSet<(PathBuf, String)>> = Mutex::new(BTreeSet::new());
+
+/// The syntaxes bundled with syntect along with the `.sublime-syntax` files
+/// in `syntaxes/`. Compiling custom syntaxes is slow, so the set is loaded
+/// once per run and kept in the cache dir, keyed by the syntax files.
+pub fn syntax_set() -> Result<&'static SyntaxSet> {
+    if let Some(syntax_set) = SYNTAX_SET.get() {
+        return Ok(syntax_set);
+    }
+    let syntax_set = load_syntaxes()?;
+    Ok(SYNTAX_SET.get_or_init(|| syntax_set))
+}
+
+fn load_syntaxes() -> Result<SyntaxSet> {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+
+    let mut sources = Vec::new();
+    for entry in WalkDir::new(SYNTAXES_DIR)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().and_then(|ext| ext.to_str()) == Some("sublime-syntax"))
+    {
+        let source = read_to_string(entry.path())
+            .with_context(|| format!("Failed to read syntax: {}", entry.path().display()))?;
+        hasher.update(entry.path().to_string_lossy().as_bytes());
+        hasher.update(&source);
+        sources.push((entry.into_path(), source));
+    }
+
+    if sources.is_empty() {
+        return Ok(SyntaxSet::load_defaults_newlines());
+    }
+
+    let key = hasher.finalize()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let cache_path = Path::new(CACHE_DIR).join(format!("syntaxes-{}.packdump", key));
+    if let Ok(syntax_set) = from_uncompressed_dump_file(&cache_path) {
+        return Ok(syntax_set);
+    }
+
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    for (path, source) in sources {
+        let name = path.file_stem().and_then(|stem| stem.to_str());
+        let syntax = SyntaxDefinition::load_from_str(&source, true, name)
+            .with_context(|| format!("Failed to load syntax: {}", path.display()))?;
+        builder.add(syntax);
+    }
+    let syntax_set = builder.build();
+
+    create_dir_all(CACHE_DIR).context("Failed to create cache directory")?;
+    dump_to_uncompressed_file(&syntax_set, &cache_path)
+        .with_context(|| format!("Failed to cache syntaxes: {}", cache_path.display()))?;
+    println!("✓ Compiled syntaxes from {}", SYNTAXES_DIR);
+
+    Ok(syntax_set)
+}
+
+/// Looks up the syntax for the language of a code block, returning `None`
+/// for unknown languages. Those are meant to be highlighted as plain text.
+pub fn find_syntax<'a>(syntax_set: &'a SyntaxSet, language: &str) -> Option<&'a SyntaxReference> {
+    syntax_set.find_syntax_by_token(language).or_else(|| {
+        PLAIN_LANGUAGES
+            .contains(&language)
+            .then(|| syntax_set.find_syntax_plain_text())
+    })
+}
+
+/// Prints a warning for a code block language without a syntax, once per
+/// file and language as pages can be rendered more than once per build.
+pub fn warn_unknown_language(path: &Path, language: &str) {
+    let mut warned = WARNED_LANGUAGES.lock().unwrap_or_else(|e| e.into_inner());
+    if warned.insert((path.to_path_buf(), language.to_string())) {
+        eprintln!(
+            "⚠ Unknown language '{}' in {}, highlighted as plain text",
+            language,
+            path.display()
+        );
+    }
+}
+
+const BASE_CSS: &str = r#"
+/* Code block styling */
+.code {
+    font-family: 'Fira Code', 'Monaco', 'Cascadia Code', 'Roboto Mono', monospace;
+    font-size: 0.9rem;
+    line-height: 1.5;
+    padding: 1rem;
+    border-radius: 0.375rem;
+    overflow-x: auto;
+    margin: 1rem 0;
+    position: relative;
+}
+
+/* Line by line blocks, from the linenos, hl_lines and diff attributes */
+.code .line {
+    display: flex;
+}
+
+.code.linenos > code {
+    counter-reset: line;
+}
+
+.code.linenos .line::before {
+    counter-increment: line;
+    content: counter(line);
+    min-width: 2.5em;
+    padding-right: 1em;
+    text-align: right;
+    opacity: 0.5;
+    user-select: none;
+}
+
+.code.diff .line .cl::before {
+    content: " ";
+    padding-right: 0.5em;
+    user-select: none;
+}
+
+.code.diff .diff-add .cl::before {
+    content: "+";
+}
+
+.code.diff .diff-remove .cl::before {
+    content: "-";
+}
+
+.code-title {
+    font-family: 'Fira Code', 'Monaco', 'Cascadia Code', 'Roboto Mono', monospace;
+    font-size: 0.85rem;
+    margin: 1rem 0 -1rem;
+    padding: 0.25rem 1rem;
+}
+
+.code-copy {
+    position: absolute;
+    top: 0.5rem;
+    right: 0.5rem;
+}
+"#;
+
+const MEDIA_CSS: &str = r#"
+/* Syntax highlighting with automatic light/dark theme switching */
+@import url("theme-light.css") (prefers-color-scheme: light);
+@import url("theme-dark.css") (prefers-color-scheme: dark);
+
+/* Default to light theme for older browsers */
+@import url("theme-light.css");
+"#;
+
+/// Loads the themes bundled with syntect along with the `.tmTheme` files in
+/// `themes/`, which are named after their file stem.
+fn load_themes() -> Result<ThemeSet> {
+    let mut theme_set = ThemeSet::load_defaults();
+
+    for entry in WalkDir::new(THEMES_DIR)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().and_then(|ext| ext.to_str()) == Some("tmTheme"))
+    {
+        let theme = ThemeSet::get_theme(entry.path())
+            .with_context(|| format!("Failed to load theme: {}", entry.path().display()))?;
+        let name = entry
+            .path()
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .with_context(|| format!("Invalid theme file name: {}", entry.path().display()))?;
+        theme_set.themes.insert(name.to_string(), theme);
+    }
+
+    Ok(theme_set)
+}
+
+fn find_theme<'a>(theme_set: &'a ThemeSet, name: &str) -> Result<&'a Theme> {
+    theme_set.themes.get(name).ok_or_else(|| {
+        anyhow!(
+            "Unknown syntax theme '{}', available themes: {}",
+            name,
+            theme_set
+                .themes
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    })
+}
+
+fn hex(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+/// Css for the highlighting classes of a theme. Syntect already sets the
+/// code block colours from the theme, the border is derived from it here.
+fn theme_css(theme: &Theme, name: &str) -> Result<String> {
+    let mut css = css_for_theme_with_class_style(theme, ClassStyle::Spaced)
+        .with_context(|| format!("Failed to generate css for syntax theme: {}", name))?;
+
+    let settings = &theme.settings;
+    if let Some(border) = settings
+        .line_highlight
+        .or(settings.gutter)
+        .or(settings.background)
+    {
+        css.push_str(&format!(
+            ".code {{\n border: 1px solid {};\n}}\n",
+            hex(border)
+        ));
+    }
+    Ok(css)
+}
+
+/// Nests theme css under a selector, e.g. `.code` becomes `html.dark .code`.
+fn scope_css(selector: &str, css: &str) -> Result<String> {
+    // Drop the leading "theme generated by syntect" comment, which would be
+    // left behind as an empty rule
+    let css = match css.trim_start().strip_prefix("/*") {
+        Some(rest) => rest.split_once("*/").map_or(css, |(_, css)| css),
+        None => css,
+    };
+    grass::from_string(
+        format!("{} {{\n{}\n}}", selector, css),
+        &grass::Options::default(),
+    )
+    .map_err(|e| anyhow!("Failed to scope syntax theme css: {}", e))
+}
+
+/// Returns the syntax highlighting stylesheets, as paths relative to the
+/// output dir with their css, in the order they have to be written.
+///
+/// In `media` mode the light and dark themes live in their own files picked
+/// by `prefers-color-scheme`. In `class` mode `syntax.css` holds both, and
+/// a `dark` or `light` class on `<html>` overrides the system preference.
+pub fn syntax_stylesheets(config: &HighlightConfig) -> Result<Vec<(&'static str, String)>> {
+    let theme_set = load_themes()?;
+    let light = theme_css(
+        find_theme(&theme_set, &config.light_theme)?,
+        &config.light_theme,
+    )?;
+    let dark = theme_css(
+        find_theme(&theme_set, &config.dark_theme)?,
+        &config.dark_theme,
+    )?;
+
+    let stylesheets = match config.mode {
+        ThemeMode::Media => vec![
+            ("theme-light.css", light),
+            ("theme-dark.css", dark),
+            ("syntax.css", format!("{}{}", MEDIA_CSS, BASE_CSS)),
+        ],
+        ThemeMode::Class => vec![(
+            "syntax.css",
+            format!(
+                "{}\n{}\n@media (prefers-color-scheme: dark) {{\n{}\n}}\n{}",
+                BASE_CSS,
+                scope_css("html:not(.dark)", &light)?,
+                scope_css("html:not(.light)", &dark)?,
+                scope_css("html.dark", &dark)?,
+            ),
+        )],
+    };
+    Ok(stylesheets)
+}
+
+/// Attributes from the info string of a code fence, after the language,
+/// e.g. `title="main.rs" linenos hl_lines="3-5 8" diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeAttributes {
+    pub title: Option<String>,
+    pub linenos: bool,
+    pub hl_lines: Vec<(usize, usize)>,
+    pub diff: bool,
+    pub copy: bool,
+    /// Renders diagram languages like `dot` as svg rather than code.
+    pub diagram: bool,
+    /// Source file to include, relative to the project
+    pub file: Option<String>,
+    pub lines: Vec<(usize, usize)>,
+    pub region: Option<String>,
+    /// Words of the info string that aren't attributes, left for the caller
+    /// to warn about.
+    pub unknown: Vec<String>,
+}
+
+impl Default for CodeAttributes {
+    fn default() -> Self {
+        Self {
+            title: None,
+            linenos: false,
+            hl_lines: Vec::new(),
+            diff: false,
+            copy: true,
+            diagram: true,
+            file: None,
+            lines: Vec::new(),
+            region: None,
+            unknown: Vec::new(),
+        }
+    }
+}
+
+impl CodeAttributes {
+    pub fn parse(meta: &str) -> Result<Self> {
+        let mut attributes = Self::default();
+        for (key, value) in info_tokens(meta)? {
+            match (key, value) {
+                ("title", Some(title)) => attributes.title = Some(title.to_string()),
+                ("linenos", None) => attributes.linenos = true,
+                ("hl_lines", Some(lines)) => {
+                    attributes.hl_lines = parse_line_ranges(lines, "hl_lines")?
+                }
+                ("diff", None) => attributes.diff = true,
+                ("nocopy", None) => attributes.copy = false,
+                ("nodiagram", None) => attributes.diagram = false,
+                ("file", Some(file)) => attributes.file = Some(file.to_string()),
+                ("lines", Some(lines)) => attributes.lines = parse_line_ranges(lines, "lines")?,
+                ("region", Some(region)) => attributes.region = Some(region.to_string()),
+                ("file" | "lines" | "region", None) => {
+                    bail!("Code block attribute '{}' needs a value", key)
+                }
+                (key, _) => attributes.unknown.push(key.to_string()),
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// Whether the block is rendered line by line rather than as one run of
+    /// highlighted code.
+    pub fn wraps_lines(&self) -> bool {
+        self.linenos || self.diff || !self.hl_lines.is_empty()
+    }
+
+    pub fn is_highlighted(&self, line: usize) -> bool {
+        self.hl_lines
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&line))
+    }
+}
+
+/// Splits an info string into `key` and `key="value"` tokens.
+fn info_tokens(meta: &str) -> Result<Vec<(&str, Option<&str>)>> {
+    let mut tokens = Vec::new();
+    let mut rest = meta.trim_start();
+    while !rest.is_empty() {
+        let end = rest.find([' ', '\t', '=']).unwrap_or(rest.len());
+        let key = &rest[..end];
+        rest = &rest[end..];
+
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                if let Some(quoted) = value.strip_prefix('"') {
+                    let (value, after) = quoted.split_once('"').with_context(|| {
+                        format!("Unclosed quote in code block attribute '{}'", key)
+                    })?;
+                    rest = after;
+                    Some(value)
+                } else {
+                    let end = value.find([' ', '\t']).unwrap_or(value.len());
+                    rest = &value[end..];
+                    Some(&value[..end])
+                }
+            }
+            None => None,
+        };
+        tokens.push((key, value));
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parses line ranges like `3-5 8` or `3-5,8`, one based and inclusive.
+fn parse_line_ranges(lines: &str, attribute: &str) -> Result<Vec<(usize, usize)>> {
+    lines
+        .split([' ', ','])
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            match (start.trim().parse(), end.trim().parse()) {
+                (Ok(start), Ok(end)) if start >= 1 && start <= end => Ok((start, end)),
+                _ => Err(anyhow!("Invalid line range '{}' in {}", range, attribute)),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffLine {
+    Added,
+    Removed,
+    Context,
+}
+
+/// Strips the `+`/`-` markers from the lines of a diff block, so the rest can
+/// be highlighted as the target language.
+pub fn split_diff(code: &str) -> (String, Vec<DiffLine>) {
+    let mut stripped = String::with_capacity(code.len());
+    let mut markers = Vec::new();
+    for line in LinesWithEndings::from(code) {
+        let (marker, line) = match line.as_bytes().first() {
+            Some(b'+') => (DiffLine::Added, &line[1..]),
+            Some(b'-') => (DiffLine::Removed, &line[1..]),
+            Some(b' ') => (DiffLine::Context, &line[1..]),
+            _ => (DiffLine::Context, line),
+        };
+        markers.push(marker);
+        stripped.push_str(line);
+    }
+    (stripped, markers)
+}
+
+/// Splits highlighted html into lines. Spans that are still open at the end
+/// of a line, like those of a multi-line string, are closed there and opened
+/// again on the next line so every line can be wrapped on its own.
+pub fn split_highlighted_lines(html: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut open: Vec<&str> = Vec::new();
+    let mut line = String::new();
+    let mut has_text = false;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("</span>") {
+            open.pop();
+            line.push_str("</span>");
+            rest = after;
+        } else if rest.starts_with("<span") {
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            open.push(&rest[..end]);
+            line.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if let Some(after) = rest.strip_prefix('\n') {
+            line.push_str(&"</span>".repeat(open.len()));
+            lines.push(std::mem::replace(&mut line, open.concat()));
+            has_text = false;
+            rest = after;
+        } else {
+            let end = rest.find(['<', '\n']).unwrap_or(rest.len());
+            line.push_str(&rest[..end]);
+            has_text = true;
+            rest = &rest[end..];
+        }
+    }
+    if has_text {
+        line.push_str(&"</span>".repeat(open.len()));
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_known_attributes() {
+        let attributes =
+            CodeAttributes::parse(r#"title="main.rs" linenos hl_lines="3-5 8" diff nocopy"#)
+                .unwrap();
+        assert_eq!(attributes.title.as_deref(), Some("main.rs"));
+        assert!(attributes.linenos && attributes.diff && !attributes.copy);
+        assert_eq!(attributes.hl_lines, vec![(3, 5), (8, 8)]);
+        assert!(attributes.unknown.is_empty());
+    }
+
+    #[test]
+    fn parse_collects_unknown_words() {
+        let attributes = CodeAttributes::parse("title: x linenos").unwrap();
+        assert_eq!(attributes.unknown, vec!["title:", "x"]);
+        assert!(attributes.linenos);
+        assert_eq!(attributes.title, None);
+    }
+
+    #[test]
+    fn parse_rejects_bad_include_attributes() {
+        assert!(CodeAttributes::parse("file").is_err());
+        assert!(CodeAttributes::parse(r#"file="a.rs" lines="x""#).is_err());
+        assert!(CodeAttributes::parse(r#"title="open"#).is_err());
+    }
+
+    #[test]
+    fn parse_line_ranges_accepts_spaces_and_commas() {
+        assert_eq!(
+            parse_line_ranges("1-2, 4 6-6", "lines").unwrap(),
+            vec![(1, 2), (4, 4), (6, 6)]
+        );
+        assert!(parse_line_ranges("0", "lines").is_err());
+        assert!(parse_line_ranges("5-3", "lines").is_err());
+    }
+
+    #[test]
+    fn split_diff_strips_markers() {
+        let (code, markers) = split_diff("+added\n-removed\n kept\nplain\n");
+        assert_eq!(code, "added\nremoved\nkept\nplain\n");
+        assert_eq!(
+            markers,
+            vec![
+                DiffLine::Added,
+                DiffLine::Removed,
+                DiffLine::Context,
+                DiffLine::Context
+            ]
+        );
+    }
+
+    #[test]
+    fn split_highlighted_lines_reopens_spans() {
+        let lines = split_highlighted_lines("<span class=\"s\">\"a\nb\"</span>\nc\n");
+        assert_eq!(
+            lines,
+            vec![
+                "<span class=\"s\">\"a</span>",
+                "<span class=\"s\">b\"</span>",
+                "c",
+            ]
+        );
+    }
+}
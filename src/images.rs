@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use image::{
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader,
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    metadata::Orientation,
+};
+use img_parts::{Bytes, DynImage, ImageEXIF};
+use sha2::{Digest, Sha256};
+use tokio::fs::{create_dir_all, metadata, read, try_exists, write};
+
+use crate::{
+    consts::{CACHE_DIR, ImagesConfig},
+    utils::{copy_if_changed, escape_attribute},
+};
+
+#[derive(Debug, Clone)]
+pub struct ImageSource {
+    pub url: String,
+    pub width: u32,
+}
+
+/// An image from `static/` or a page bundle along with the variants written
+/// for it. Sources are ordered by width and end with the full size image.
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    pub width: u32,
+    pub height: u32,
+    pub sources: Vec<ImageSource>,
+    pub webp_sources: Vec<ImageSource>,
+}
+
+/// Processed images keyed by the url of the original, e.g. `/images/cat.jpg`.
+#[derive(Debug, Default)]
+pub struct ImageSet {
+    images: HashMap<String, ProcessedImage>,
+    sizes: String,
+}
+
+impl ImageSet {
+    pub fn new(sizes: &str) -> Self {
+        Self {
+            images: HashMap::new(),
+            sizes: sizes.to_string(),
+        }
+    }
+
+    pub fn insert(&mut self, url: String, image: ProcessedImage) {
+        self.images.insert(url, image);
+    }
+
+    /// Responsive markup for a markdown image, or `None` when `url` isn't a
+    /// processed image.
+    pub fn picture(&self, url: &str, alt: &str, title: &str) -> Option<String> {
+        let image = self.images.get(url)?;
+        let sizes = escape_attribute(&self.sizes);
+        let fallback = &image.sources[image.sources.len() - 1];
+
+        let mut img = format!(
+            r#"<img src="{}" srcset="{}" sizes="{}" width="{}" height="{}" alt="{}""#,
+            escape_attribute(&fallback.url),
+            srcset(&image.sources),
+            sizes,
+            image.width,
+            image.height,
+            escape_attribute(alt)
+        );
+        if !title.is_empty() {
+            img.push_str(&format!(r#" title="{}""#, escape_attribute(title)));
+        }
+        img.push_str(r#" loading="lazy" decoding="async" />"#);
+
+        if image.webp_sources.is_empty() {
+            return Some(img);
+        }
+        Some(format!(
+            r#"<picture><source type="image/webp" srcset="{}" sizes="{}" />{}</picture>"#,
+            srcset(&image.webp_sources),
+            sizes,
+            img
+        ))
+    }
+}
+
+fn srcset(sources: &[ImageSource]) -> String {
+    sources
+        .iter()
+        .map(|source| format!("{} {}w", escape_attribute(&source.url), source.width))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub fn is_image(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref(),
+        Some("jpg" | "jpeg" | "png" | "webp")
+    )
+}
+
+/// Writes the resized, metadata free variants of an image next to where the
+/// original would go in the output dir. Variants are kept in the cache dir,
+/// keyed by the content of the original and the encoder settings, so
+/// unchanged images are only processed once.
+pub async fn process_image(
+    source: &Path,
+    rel_path: &str,
+    output_dir: &Path,
+    config: &ImagesConfig,
+) -> Result<(ProcessedImage, Vec<PathBuf>)> {
+    let bytes = read(source)
+        .await
+        .with_context(|| format!("Failed to read image: {}", source.display()))?;
+    let key = Sha256::digest(&bytes)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let format = ImageFormat::from_path(source)?;
+    // The decoder isn't `Send`, so it can't be held across an await
+    let (orientation, (width, height)) = {
+        let mut decoder = ImageReader::new(Cursor::new(&bytes))
+            .with_guessed_format()?
+            .into_decoder()
+            .with_context(|| format!("Unsupported image: {}", source.display()))?;
+        let orientation = decoder.orientation()?;
+        let dimensions = match (decoder.dimensions(), orientation) {
+            (
+                (width, height),
+                Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH,
+            ) => (height, width),
+            (dimensions, _) => dimensions,
+        };
+        (orientation, dimensions)
+    };
+
+    let mut widths = config
+        .widths
+        .iter()
+        .copied()
+        .filter(|w| *w < width)
+        .collect::<Vec<u32>>();
+    widths.sort_unstable();
+    widths.dedup();
+    widths.push(width);
+
+    let stem = rel_path.rsplit_once('.').map_or(rel_path, |(stem, _)| stem);
+    let extension = rel_path.rsplit_once('.').map_or("", |(_, ext)| ext);
+    let cache_dir = Path::new(CACHE_DIR).join("images");
+
+    // Only decoded when a variant is missing from the cache
+    let mut decoded = None;
+
+    let mut processed = ProcessedImage {
+        width,
+        height,
+        sources: Vec::new(),
+        webp_sources: Vec::new(),
+    };
+    let mut outputs = Vec::new();
+
+    for variant_width in widths {
+        let full_size = variant_width == width;
+        let variant_height = (height as u64 * variant_width as u64 / width as u64).max(1) as u32;
+
+        let path = if full_size {
+            rel_path.to_string()
+        } else {
+            format!("{}-{}w.{}", stem, variant_width, extension)
+        };
+        let cache_path = cache_dir.join(cache_name(
+            &key,
+            variant_width,
+            format,
+            extension,
+            config.quality,
+        ));
+        if !try_exists(&cache_path).await? {
+            let encoded = if full_size && orientation == Orientation::NoTransforms {
+                strip_metadata(&bytes)?
+            } else {
+                let image = decoded_image(&mut decoded, &bytes, orientation, source)?;
+                let resized =
+                    image.resize_exact(variant_width, variant_height, FilterType::Lanczos3);
+                encode_image(&resized, format, config.quality)?
+            };
+            write_cache(&cache_path, &encoded).await?;
+        }
+        let fallback_size = metadata(&cache_path).await?.len();
+        outputs.push(copy_variant(&cache_path, output_dir, &path).await?);
+        processed.sources.push(ImageSource {
+            url: format!("/{}", path),
+            width: variant_width,
+        });
+
+        if !config.webp || format == ImageFormat::WebP {
+            continue;
+        }
+        let webp_path = if full_size {
+            format!("{}.webp", stem)
+        } else {
+            format!("{}-{}w.webp", stem, variant_width)
+        };
+        let webp_cache_path = cache_dir.join(cache_name(
+            &key,
+            variant_width,
+            ImageFormat::WebP,
+            "webp",
+            config.quality,
+        ));
+        if !try_exists(&webp_cache_path).await? {
+            let image = decoded_image(&mut decoded, &bytes, orientation, source)?;
+            let resized;
+            let image = if full_size {
+                image
+            } else {
+                resized = image.resize_exact(variant_width, variant_height, FilterType::Lanczos3);
+                &resized
+            };
+            write_cache(
+                &webp_cache_path,
+                &encode_image(image, ImageFormat::WebP, config.quality)?,
+            )
+            .await?;
+        }
+        // The WebP encoder is lossless, which can lose to a lossy original
+        if metadata(&webp_cache_path).await?.len() < fallback_size {
+            outputs.push(copy_variant(&webp_cache_path, output_dir, &webp_path).await?);
+            processed.webp_sources.push(ImageSource {
+                url: format!("/{}", webp_path),
+                width: variant_width,
+            });
+        }
+    }
+
+    Ok((processed, outputs))
+}
+
+/// Name of a cached variant. It includes the settings `encode_image` uses
+/// for the format, so changing them re-encodes the variants.
+fn cache_name(key: &str, width: u32, format: ImageFormat, extension: &str, quality: u8) -> String {
+    let encoder = match format {
+        ImageFormat::Jpeg => format!("q{}", quality),
+        ImageFormat::WebP => "lossless".to_string(),
+        _ => "default".to_string(),
+    };
+    format!("{}-{}w-{}.{}", key, width, encoder, extension)
+}
+
+fn decoded_image<'a>(
+    slot: &'a mut Option<DynamicImage>,
+    bytes: &[u8],
+    orientation: Orientation,
+    source: &Path,
+) -> Result<&'a DynamicImage> {
+    let image = match slot.take() {
+        Some(image) => image,
+        None => {
+            let mut image = ImageReader::new(Cursor::new(bytes))
+                .with_guessed_format()?
+                .decode()
+                .with_context(|| format!("Failed to decode image: {}", source.display()))?;
+            image.apply_orientation(orientation);
+            image
+        }
+    };
+    Ok(slot.insert(image))
+}
+
+/// Drops the EXIF block of an image without re-encoding it.
+fn strip_metadata(bytes: &[u8]) -> Result<Vec<u8>> {
+    let Some(mut image) = DynImage::from_bytes(Bytes::copy_from_slice(bytes))
+        .map_err(|e| anyhow!("Failed to read image metadata: {}", e))?
+    else {
+        return Ok(bytes.to_vec());
+    };
+    image.set_exif(None);
+    Ok(image.encoder().bytes().to_vec())
+}
+
+fn encode_image(image: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut encoded, quality);
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        ImageFormat::WebP => {
+            let image = if image.color().has_alpha() {
+                DynamicImage::ImageRgba8(image.to_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            };
+            image.write_with_encoder(WebPEncoder::new_lossless(&mut encoded))?;
+        }
+        format => image.write_to(&mut Cursor::new(&mut encoded), format)?,
+    }
+    Ok(encoded)
+}
+
+async fn write_cache(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create cache directory: {}", parent.display()))?;
+    }
+    write(path, content)
+        .await
+        .with_context(|| format!("Failed to write cached image: {}", path.display()))
+}
+
+async fn copy_variant(cache_path: &Path, output_dir: &Path, path: &str) -> Result<PathBuf> {
+    let output_path = output_dir.join(path);
+    copy_if_changed(cache_path, &output_path).await?;
+    Ok(output_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_name_includes_encoder_settings() {
+        assert_eq!(
+            cache_name("0123abcd", 480, ImageFormat::Jpeg, "jpg", 80),
+            "0123abcd-480w-q80.jpg"
+        );
+        assert_ne!(
+            cache_name("0123abcd", 480, ImageFormat::Jpeg, "jpg", 80),
+            cache_name("0123abcd", 480, ImageFormat::Jpeg, "jpg", 60)
+        );
+        assert_eq!(
+            cache_name("0123abcd", 480, ImageFormat::WebP, "webp", 80),
+            "0123abcd-480w-lossless.webp"
+        );
+        assert_eq!(
+            cache_name("0123abcd", 480, ImageFormat::Png, "png", 80),
+            "0123abcd-480w-default.png"
+        );
+    }
+}
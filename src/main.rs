@@ -1,13 +1,30 @@
+mod admonition;
+mod assets;
 mod cli;
+mod compress;
 mod consts;
+mod date;
+mod diagram;
+mod highlight;
+mod images;
+mod math;
+mod permalink;
+mod redirect;
+mod sanitize;
+mod snippet;
 mod utils;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use cli::{
-    add::add_content, build::build_content, clean::clean_content, init::init_project,
-    list::list_content, serve::serve_content,
+    add::add_content,
+    build::{BuildOptions, build_content},
+    clean::clean_content,
+    init::init_project,
+    list::list_content,
+    serve::serve_content,
 };
+use consts::BuildProfile;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -35,14 +52,31 @@ enum Commands {
     Build {
         #[arg(long)]
         include_drafts: bool,
+        #[arg(long, help = "Include pages whose date is in the future")]
+        include_future: bool,
         #[arg(short('o'), long, default_value = "public")]
         output_dir: String,
+        #[arg(long, value_enum, default_value_t = BuildProfile::Development)]
+        profile: BuildProfile,
+        #[arg(long, help = "Minify the html of rendered pages")]
+        minify: bool,
     },
     Serve {
         #[arg(short('p'), long, default_value = "5000")]
         port: u16,
         #[arg(long)]
         open: bool,
+        #[arg(long, help = "Include drafts when rebuilding")]
+        include_drafts: bool,
+        #[arg(
+            long,
+            help = "Include pages whose date is in the future when rebuilding"
+        )]
+        include_future: bool,
+        #[arg(long, value_enum, default_value_t = BuildProfile::Development)]
+        profile: BuildProfile,
+        #[arg(long, help = "Minify the html of rebuilt pages")]
+        minify: bool,
     },
     List {
         #[arg(short('l'), long("list"))]
@@ -65,9 +99,36 @@ async fn main() -> Result<()> {
         Commands::Clean { directory } => clean_content(directory).await?,
         Commands::Build {
             include_drafts,
+            include_future,
             output_dir,
-        } => build_content(*include_drafts, output_dir).await?,
-        Commands::Serve { port, open } => serve_content(*port, *open).await?,
+            profile,
+            minify,
+        } => {
+            build_content(
+                *include_drafts,
+                *include_future,
+                *profile,
+                *minify,
+                output_dir,
+            )
+            .await?
+        }
+        Commands::Serve {
+            port,
+            open,
+            include_drafts,
+            include_future,
+            profile,
+            minify,
+        } => {
+            let options = BuildOptions {
+                include_drafts: *include_drafts,
+                include_future: *include_future,
+                profile: *profile,
+                minify_html: *minify,
+            };
+            serve_content(*port, *open, options).await?
+        }
     }
     Ok(())
 }
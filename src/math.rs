@@ -0,0 +1,33 @@
+use anyhow::{Result, anyhow};
+use katex::{Opts, OutputType};
+
+/// Renders a LaTeX expression into MathML with KaTeX, so pages don't need
+/// a math script. Display math is rendered as a block.
+pub fn math_to_mathml(expression: &str, display: bool) -> Result<String> {
+    let opts = Opts::builder()
+        .display_mode(display)
+        .output_type(OutputType::Mathml)
+        .throw_on_error(true)
+        .build()
+        .map_err(|e| anyhow!("Invalid math options: {}", e))?;
+    katex::render_with_opts(expression, opts).map_err(|e| anyhow!(parse_error_message(&e)))
+}
+
+/// KaTeX parse errors come wrapped in the details of the js exception, e.g.
+/// `String("ParseError: KaTeX parse error: ... at position 1: \\\u{332}n...")`.
+/// Keeps the message and position, dropping the underlined source.
+fn parse_error_message(error: &katex::Error) -> String {
+    let message = error.to_string();
+    let Some((_, parse_error)) = message.split_once("KaTeX parse error: ") else {
+        return message;
+    };
+    let parse_error = parse_error.trim_end_matches("\"))").replace("\\\\", "\\");
+    match parse_error.split_once(" at position ") {
+        Some((message, position)) => format!(
+            "{} at position {}",
+            message,
+            position.split(':').next().unwrap_or(position)
+        ),
+        None => parse_error,
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, Datelike, FixedOffset};
+
+use crate::{consts::Config, utils::normalize_path};
+
+const STATIC_PATTERN: &str = "/:slug.html";
+const SECTION_PATTERN: &str = "/:section/:slug.html";
+
+/// Returns the permalink pattern configured for a section, falling back to
+/// `/:slug.html` for static pages and `/:section/:slug.html` for the rest.
+pub fn permalink_pattern<'a>(config: &'a Config, section: &str) -> &'a str {
+    match config.permalinks.get(section) {
+        Some(pattern) => pattern,
+        None if section == "static" => STATIC_PATTERN,
+        None => SECTION_PATTERN,
+    }
+}
+
+/// Expands the `:year`, `:month`, `:day`, `:section` and `:slug` tokens of a
+/// permalink pattern. Patterns ending in `/` are pretty urls, and `.html`
+/// is appended to patterns that don't name a file extension.
+pub fn resolve_permalink(
+    pattern: &str,
+    section: &str,
+    slug: &str,
+    date: Option<&DateTime<FixedOffset>>,
+) -> Result<String> {
+    let mut url = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    // Tokens are matched whole on the pattern, so `:slugx` is unknown
+    // rather than `:slug` followed by `x`, and a `:` the slug or section
+    // brings in is never taken for one
+    while let Some(start) = rest.find(':') {
+        url.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = after
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(after.len());
+        let (name, remainder) = after.split_at(end);
+        rest = remainder;
+
+        match name {
+            "year" | "month" | "day" => {
+                let Some(date) = date else {
+                    bail!(
+                        "Permalink pattern '{}' uses the date but page '{}' has none",
+                        pattern,
+                        slug
+                    );
+                };
+                url.push_str(&match name {
+                    "year" => format!("{:04}", date.year()),
+                    "month" => format!("{:02}", date.month()),
+                    _ => format!("{:02}", date.day()),
+                });
+            }
+            "section" => url.push_str(section),
+            "slug" => url.push_str(slug),
+            "" => url.push(':'),
+            name => bail!(
+                "Unknown token ':{}' in permalink pattern '{}'",
+                name,
+                pattern
+            ),
+        }
+    }
+    url.push_str(rest);
+
+    if !url.starts_with('/') {
+        url.insert(0, '/');
+    }
+
+    // Only the pattern decides, as slugs like `release-1.2` can have dots
+    let has_extension = pattern
+        .rsplit('/')
+        .next()
+        .and_then(|file| Path::new(file).extension())
+        .is_some_and(|ext| !ext.to_string_lossy().contains(':'));
+    if !url.ends_with('/') && !has_extension {
+        url.push_str(".html");
+    }
+
+    Ok(url)
+}
+
+/// Resolves a relative url, like a link in a page bundle, against the url
+/// of the page it appears on. Absolute urls and urls with a scheme are kept
+/// as they are.
+pub fn resolve_relative_url(url: &str, page_url: &str) -> String {
+    let path_end = url.find(['?', '#']).unwrap_or(url.len());
+    let (path, suffix) = url.split_at(path_end);
+    if url.starts_with('/')
+        || path
+            .split('/')
+            .next()
+            .is_some_and(|first| first.contains(':'))
+    {
+        return url.to_string();
+    }
+    if path.is_empty() {
+        return format!("{}{}", page_url, suffix);
+    }
+
+    let base = page_url.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let mut segments = base
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<&str>>();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut resolved = format!("/{}", segments.join("/"));
+    if path.ends_with('/') && !resolved.ends_with('/') {
+        resolved.push('/');
+    }
+    resolved.push_str(suffix);
+    resolved
+}
+
+/// Maps a permalink onto the file it is written to inside the output dir,
+/// failing for urls that would end up outside of it, e.g. through a slug
+/// with `..` in it.
+pub fn permalink_output_path(output_dir: &Path, url: &str) -> Result<PathBuf> {
+    let mut path = normalize_path(&output_dir.join(url.trim_start_matches('/')));
+    if url.ends_with('/') {
+        path.push("index.html");
+    }
+    if !path.starts_with(normalize_path(output_dir)) || path == normalize_path(output_dir) {
+        bail!("Url '{}' resolves outside the output directory", url);
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_permalink_expands_tokens() {
+        let date = DateTime::parse_from_rfc3339("2024-03-05T10:00:00+00:00").unwrap();
+        assert_eq!(
+            resolve_permalink("/:year/:month/:day/:slug/", "blog", "post", Some(&date)).unwrap(),
+            "/2024/03/05/post/"
+        );
+        assert_eq!(
+            resolve_permalink(SECTION_PATTERN, "blog", "post", None).unwrap(),
+            "/blog/post.html"
+        );
+        assert_eq!(
+            resolve_permalink("posts/:slug.json", "blog", "post", None).unwrap(),
+            "/posts/post.json"
+        );
+    }
+
+    #[test]
+    fn resolve_permalink_ignores_dots_in_the_slug() {
+        assert_eq!(
+            resolve_permalink("/:section/:slug", "blog", "release-1.2", None).unwrap(),
+            "/blog/release-1.2.html"
+        );
+    }
+
+    #[test]
+    fn resolve_permalink_rejects_bad_patterns() {
+        assert!(resolve_permalink("/:year/:slug/", "blog", "post", None).is_err());
+        assert!(resolve_permalink("/:category/:slug/", "blog", "post", None).is_err());
+        assert!(resolve_permalink("/:slugx/", "blog", "post", None).is_err());
+        assert!(resolve_permalink("/:section/:yearly/", "blog", "post", None).is_err());
+    }
+
+    #[test]
+    fn resolve_permalink_keeps_colons_from_the_page() {
+        assert_eq!(
+            resolve_permalink("/:section/:slug/", "notes:rust", "re:post", None).unwrap(),
+            "/notes:rust/re:post/"
+        );
+        assert_eq!(
+            resolve_permalink("/:section/:slug/", "blog", ":year", None).unwrap(),
+            "/blog/:year/"
+        );
+    }
+
+    #[test]
+    fn permalink_output_path_stays_in_the_output_dir() {
+        let output_dir = Path::new("public");
+        assert_eq!(
+            permalink_output_path(output_dir, "/blog/post/").unwrap(),
+            Path::new("public/blog/post/index.html")
+        );
+        assert_eq!(
+            permalink_output_path(output_dir, "/about.html").unwrap(),
+            Path::new("public/about.html")
+        );
+        assert!(permalink_output_path(output_dir, "/../../escaped/").is_err());
+        assert!(permalink_output_path(output_dir, "/blog/../..").is_err());
+    }
+
+    #[test]
+    fn resolve_relative_url_resolves_against_the_page_directory() {
+        assert_eq!(
+            resolve_relative_url("diagram.png", "/blog/post/"),
+            "/blog/post/diagram.png"
+        );
+        assert_eq!(
+            resolve_relative_url("./img/a.png?v=1", "/blog/post.html"),
+            "/blog/img/a.png?v=1"
+        );
+        assert_eq!(
+            resolve_relative_url("../other/", "/blog/post/"),
+            "/blog/other/"
+        );
+        assert_eq!(resolve_relative_url("../../..", "/blog/post/"), "/");
+    }
+
+    #[test]
+    fn resolve_relative_url_points_fragments_at_the_page() {
+        assert_eq!(
+            resolve_relative_url("#setup", "/blog/post/"),
+            "/blog/post/#setup"
+        );
+    }
+
+    #[test]
+    fn resolve_relative_url_keeps_absolute_urls() {
+        for url in [
+            "/images/a.png",
+            "https://example.com/a",
+            "mailto:me@example.com",
+            "//cdn.example.com/a.js",
+        ] {
+            assert_eq!(resolve_relative_url(url, "/blog/post/"), url);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+
+use crate::utils::escape_attribute;
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub from: String,
+    pub to: String,
+    pub source: PathBuf,
+}
+
+/// Turns a front matter alias into a url. Aliases without a file extension
+/// are treated as pretty urls, so `/old-post` is served from
+/// `/old-post/index.html`. Aliases can't climb out of the site with `..`,
+/// nor contain characters that would break the line of the `_redirects`
+/// file or nginx map they end up in.
+pub fn normalize_alias(alias: &str) -> Result<String> {
+    if let Some(c) = alias
+        .trim()
+        .chars()
+        .find(|c| c.is_whitespace() || c.is_control() || matches!(c, ';' | '"' | '\'' | '\\'))
+    {
+        bail!("Alias '{}' can't contain {:?}", alias, c);
+    }
+
+    let mut segments = Vec::new();
+    for segment in alias.trim().split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => bail!("Alias '{}' can't contain '..'", alias),
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut url = format!("/{}", segments.join("/"));
+    let has_extension = segments.last().is_some_and(|file| file.contains('.'));
+    if !url.ends_with('/') && (alias.trim().ends_with('/') || !has_extension) {
+        url.push('/');
+    }
+    Ok(url)
+}
+
+pub fn redirect_stub(to: &str) -> String {
+    let to = escape_attribute(to);
+    format!(
+        r#"<!doctype html>
+<html lang="en">
+	<head>
+		<meta charset="UTF-8" />
+		<title>Redirecting…</title>
+		<link rel="canonical" href="{to}" />
+		<meta name="robots" content="noindex" />
+		<meta http-equiv="refresh" content="0; url={to}" />
+	</head>
+	<body>
+		<p>This page has moved to <a href="{to}">{to}</a>.</p>
+	</body>
+</html>
+"#
+    )
+}
+
+/// Netlify/Cloudflare Pages style `_redirects` file.
+pub fn redirects_file(redirects: &[Redirect]) -> String {
+    redirects
+        .iter()
+        .map(|redirect| format!("{} {} 301\n", redirect.from, redirect.to))
+        .collect()
+}
+
+/// Entries for an nginx `map`, meant to be pulled in with
+/// `map $uri $redirect_uri { include nginx-redirects.map; }`. Both sides
+/// are quoted, as page urls come from slugs that may contain anything.
+pub fn nginx_map_file(redirects: &[Redirect]) -> String {
+    redirects
+        .iter()
+        .map(|redirect| {
+            format!(
+                "{} {};\n",
+                nginx_string(&redirect.from),
+                nginx_string(&redirect.to)
+            )
+        })
+        .collect()
+}
+
+fn nginx_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_alias_makes_pretty_urls() {
+        assert_eq!(normalize_alias("old-post").unwrap(), "/old-post/");
+        assert_eq!(normalize_alias(" /2020/old/ ").unwrap(), "/2020/old/");
+        assert_eq!(normalize_alias("/old.html").unwrap(), "/old.html");
+        assert_eq!(normalize_alias("./a//b").unwrap(), "/a/b/");
+        assert_eq!(normalize_alias("/v1.2/").unwrap(), "/v1.2/");
+    }
+
+    #[test]
+    fn normalize_alias_rejects_parent_segments() {
+        assert!(normalize_alias("../../escaped-alias").is_err());
+        assert!(normalize_alias("/blog/../../x").is_err());
+    }
+
+    #[test]
+    fn normalize_alias_rejects_characters_that_break_redirect_files() {
+        for alias in [
+            "/old post/",
+            "/old\tpost",
+            "/a; return 200",
+            "/\"quoted\"",
+            "/it's",
+            "/back\\slash",
+            "/line\nbreak",
+            "/bell\u{7}",
+        ] {
+            assert!(normalize_alias(alias).is_err(), "{:?}", alias);
+        }
+        assert_eq!(normalize_alias("  /old-post  ").unwrap(), "/old-post/");
+    }
+
+    #[test]
+    fn nginx_map_file_quotes_urls() {
+        let redirects = [Redirect {
+            from: "/old/".to_string(),
+            to: "/blog/say \"hi\"; now/".to_string(),
+            source: PathBuf::from("content/blog/hi.md"),
+        }];
+        assert_eq!(
+            nginx_map_file(&redirects),
+            "\"/old/\" \"/blog/say \\\"hi\\\"; now/\";\n"
+        );
+    }
+}
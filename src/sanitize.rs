@@ -0,0 +1,341 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use ammonia::Builder;
+
+use crate::consts::SanitizeConfig;
+
+/// Tags grimoire renders itself, on top of ammonia's defaults.
+const TAGS: [&str; 4] = ["input", "picture", "section", "source"];
+
+/// The MathML rendered for `$…$` math.
+const MATHML_TAGS: [&str; 25] = [
+    "math",
+    "annotation",
+    "menclose",
+    "mfrac",
+    "mi",
+    "mn",
+    "mo",
+    "mover",
+    "mpadded",
+    "mphantom",
+    "mroot",
+    "mrow",
+    "mspace",
+    "msqrt",
+    "mstyle",
+    "msub",
+    "msubsup",
+    "msup",
+    "mtable",
+    "mtd",
+    "mtext",
+    "mtr",
+    "munder",
+    "munderover",
+    "semantics",
+];
+
+/// The svg rendered for diagram code fences.
+const SVG_TAGS: [&str; 14] = [
+    "svg", "circle", "clipPath", "defs", "ellipse", "g", "line", "marker", "path", "polygon",
+    "rect", "text", "textPath", "tspan",
+];
+
+/// Styling attributes allowed on every svg tag.
+const SVG_ATTRIBUTES: [&str; 11] = [
+    "clip-path",
+    "dominant-baseline",
+    "fill",
+    "font-family",
+    "font-size",
+    "marker-end",
+    "marker-start",
+    "stroke",
+    "stroke-width",
+    "text-anchor",
+    "transform",
+];
+
+/// Tags grimoire renders with a class, like the code blocks, admonitions,
+/// footnotes and task lists.
+const CLASS_TAGS: [&str; 14] = [
+    "a", "aside", "code", "div", "figure", "input", "li", "ol", "p", "pre", "section", "span",
+    "sup", "ul",
+];
+
+/// Tags grimoire renders with an id: heading anchors and footnotes, on top
+/// of the svg tags of diagrams.
+const ID_TAGS: [&str; 2] = ["a", "li"];
+
+/// Prefixes of the ids grimoire renders. Other ids are removed so untrusted
+/// content can't clobber globals like `window.config`.
+const ID_PREFIXES: [&str; 4] = ["user-content-", "fn-", "fnref-", "diagram-"];
+
+/// Attributes grimoire renders itself, like the footnote links, the
+/// responsive images, math and diagrams.
+const TAG_ATTRIBUTES: [(&str, &[&str]); 30] = [
+    (
+        "a",
+        &[
+            "aria-hidden",
+            "aria-label",
+            "data-footnote-ref",
+            "data-footnote-backref",
+            "data-footnote-backref-idx",
+        ],
+    ),
+    ("img", &["srcset", "sizes", "loading", "decoding"]),
+    ("input", &["type", "checked", "disabled"]),
+    ("pre", &["lang", "data-copy"]),
+    ("section", &["data-footnotes"]),
+    ("source", &["type", "srcset", "sizes"]),
+    ("math", &["xmlns", "display"]),
+    ("annotation", &["encoding"]),
+    ("menclose", &["notation"]),
+    ("mfrac", &["linethickness"]),
+    ("mi", &["mathvariant"]),
+    (
+        "mo",
+        &[
+            "accent",
+            "fence",
+            "largeop",
+            "lspace",
+            "maxsize",
+            "minsize",
+            "movablelimits",
+            "rspace",
+            "separator",
+            "stretchy",
+            "symmetric",
+        ],
+    ),
+    ("mover", &["accent"]),
+    (
+        "mpadded",
+        &["depth", "height", "lspace", "voffset", "width"],
+    ),
+    ("mspace", &["depth", "height", "linebreak", "width"]),
+    ("mstyle", &["displaystyle", "mathcolor", "scriptlevel"]),
+    (
+        "mtable",
+        &["columnalign", "columnlines", "columnspacing", "rowspacing"],
+    ),
+    ("mtd", &["columnalign"]),
+    ("munder", &["accentunder"]),
+    ("svg", &["height", "viewBox", "width", "xmlns"]),
+    ("circle", &["cx", "cy", "r"]),
+    ("ellipse", &["cx", "cy", "rx", "ry"]),
+    ("line", &["x1", "x2", "y1", "y2"]),
+    (
+        "marker",
+        &["markerHeight", "markerWidth", "orient", "refX", "refY"],
+    ),
+    ("path", &["d"]),
+    ("polygon", &["points"]),
+    ("rect", &["height", "rx", "ry", "width", "x", "y"]),
+    ("text", &["x", "y"]),
+    ("textPath", &["href", "startOffset"]),
+    ("tspan", &["dx", "dy", "x", "y"]),
+];
+
+static REPORTED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Cleans rendered html against the allow-list, returning it along with
+/// what was stripped, e.g. `<script>` or `onclick on <a>`, and how often.
+pub fn sanitize_html(html: &str, config: &SanitizeConfig) -> (String, BTreeMap<String, usize>) {
+    let mut builder = Builder::default();
+    builder
+        .add_tags(TAGS)
+        .add_tags(MATHML_TAGS)
+        .add_tags(SVG_TAGS)
+        .add_tags(config.tags.iter().map(String::as_str))
+        .attribute_filter(|_, attribute, value| {
+            if attribute == "id" && !ID_PREFIXES.iter().any(|prefix| value.starts_with(prefix)) {
+                return None;
+            }
+            Some(value.into())
+        });
+    for (tag, attributes) in TAG_ATTRIBUTES {
+        builder.add_tag_attributes(tag, attributes.iter().copied());
+    }
+    for tag in CLASS_TAGS {
+        builder.add_tag_attributes(tag, ["class"]);
+    }
+    for tag in ID_TAGS.into_iter().chain(SVG_TAGS) {
+        builder.add_tag_attributes(tag, ["id"]);
+    }
+    for tag in SVG_TAGS {
+        builder.add_tag_attributes(tag, SVG_ATTRIBUTES);
+    }
+    for (tag, attributes) in &config.attributes {
+        let attributes = attributes.iter().map(String::as_str);
+        if tag == "*" {
+            builder.add_generic_attributes(attributes);
+        } else {
+            builder.add_tag_attributes(tag.as_str(), attributes);
+        }
+    }
+    let sanitized = builder.clean(html).to_string();
+
+    let after = markup_counts(&sanitized);
+    let stripped = markup_counts(html)
+        .into_iter()
+        .filter_map(|(markup, count)| {
+            let removed = count.saturating_sub(after.get(&markup).copied().unwrap_or(0));
+            (removed > 0).then_some((markup, removed))
+        })
+        .collect();
+    (sanitized, stripped)
+}
+
+/// Prints what was stripped from a page, once per file as pages can be
+/// rendered more than once per build.
+pub fn report_stripped(path: &Path, stripped: &BTreeMap<String, usize>) {
+    if stripped.is_empty() {
+        return;
+    }
+    let mut reported = REPORTED.lock().unwrap_or_else(|e| e.into_inner());
+    if reported.insert(path.to_path_buf()) {
+        eprintln!(
+            "⚠ Sanitised {}, removed {}",
+            path.display(),
+            stripped
+                .iter()
+                .map(|(markup, count)| format!("{} ({})", markup, count))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+}
+
+/// Counts the start tags and their attributes in html.
+fn markup_counts(html: &str) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = comment.split_once("-->").map_or("", |(_, after)| after);
+            continue;
+        }
+
+        let end = tag_end(rest);
+        let tag = &rest[..end];
+        rest = &rest[end..];
+        let name_end = tag
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            continue;
+        }
+
+        *counts.entry(format!("<{}>", name)).or_default() += 1;
+        for attribute in attribute_names(&tag[name_end..]) {
+            *counts
+                .entry(format!("{} on <{}>", attribute.to_ascii_lowercase(), name))
+                .or_default() += 1;
+        }
+
+        // The content of raw text elements isn't markup
+        if name == "script" || name == "style" {
+            let close = format!("</{}", name);
+            rest = rest
+                .to_ascii_lowercase()
+                .find(&close)
+                .map_or("", |i| &rest[i..]);
+        }
+    }
+    counts
+}
+
+/// The position of the `>` closing a tag, skipping quoted attribute values.
+fn tag_end(tag: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return i,
+            _ => {}
+        }
+    }
+    tag.len()
+}
+
+fn attribute_names(attributes: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = attributes;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            return names;
+        }
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        names.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+
+        if let Some(value) = rest.strip_prefix('=') {
+            let value = value.trim_start();
+            rest = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    value[1..].split_once(quote).map_or("", |(_, after)| after)
+                }
+                _ => {
+                    let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                    &value[end..]
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_counts_counts_tags_and_attributes() {
+        let counts = markup_counts(
+            "<p class=\"a\" id=x>1 <!-- <b> --> <img src='a>b' onerror=go /></p>\
+             <script>if (a<b) {}</script>",
+        );
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            [
+                ("<img>", 1),
+                ("<p>", 1),
+                ("<script>", 1),
+                ("class on <p>", 1),
+                ("id on <p>", 1),
+                ("onerror on <img>", 1),
+                ("src on <img>", 1),
+            ]
+            .map(|(markup, count)| (markup.to_string(), count))
+        );
+    }
+
+    #[test]
+    fn sanitize_html_keeps_only_generated_ids() {
+        let (html, stripped) = sanitize_html(
+            "<h2><a class=\"anchor\" id=\"user-content-a\"></a>A</h2>\
+             <form id=\"config\"><img id=\"config\" class=\"x\" src=\"a.png\"></form>",
+            &SanitizeConfig::default(),
+        );
+        assert!(html.contains("id=\"user-content-a\""));
+        assert!(!html.contains("id=\"config\""));
+        assert!(!html.contains("class=\"x\""));
+        assert_eq!(stripped.get("id on <img>"), Some(&1));
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    fs::read_to_string,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::highlight::CodeAttributes;
+
+/// Marks the start of a region in an included file, e.g. `// region: setup`.
+const REGION_START: &str = "region:";
+/// Marks the end of a region, e.g. `// endregion: setup`.
+const REGION_END: &str = "endregion:";
+
+/// Returns the code a fence includes with its `file`, `lines` and `region`
+/// attributes, or `None` when the fence has no `file`. Paths are relative to
+/// the project `root`, and a missing file, region or range fails the build so
+/// quoted code can't silently drift from its source.
+pub fn include_snippet(attributes: &CodeAttributes, root: &Path) -> Result<Option<String>> {
+    let Some(file) = &attributes.file else {
+        if !attributes.lines.is_empty() || attributes.region.is_some() {
+            bail!("The lines and region code block attributes need a file attribute");
+        }
+        return Ok(None);
+    };
+
+    let path = resolve_include(root, file)?;
+    let source =
+        read_to_string(&path).with_context(|| format!("Failed to read included file: {}", file))?;
+    let mut lines = source.lines().collect::<Vec<&str>>();
+
+    if let Some(region) = &attributes.region {
+        lines = region_lines(&lines, region)
+            .with_context(|| format!("Failed to include region '{}' of {}", region, file))?;
+    }
+
+    // Line ranges are relative to the region when there is one
+    if !attributes.lines.is_empty() {
+        let mut selected = Vec::new();
+        for &(start, end) in &attributes.lines {
+            if end > lines.len() {
+                bail!(
+                    "Line range {}-{} is past the end of {} ({} lines)",
+                    start,
+                    end,
+                    file,
+                    lines.len()
+                );
+            }
+            selected.extend_from_slice(&lines[start - 1..end]);
+        }
+        lines = selected;
+    }
+
+    Ok(Some(dedent(&lines)))
+}
+
+/// Resolves an included file against the project root, rejecting absolute
+/// paths and paths that lead out of the project, through `..` or a symlink.
+fn resolve_include(root: &Path, file: &str) -> Result<PathBuf> {
+    if Path::new(file)
+        .components()
+        .any(|component| matches!(component, Component::RootDir | Component::Prefix(_)))
+    {
+        bail!("Included file '{}' must be relative to the project", file);
+    }
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve project directory: {}", root.display()))?;
+    let path = root
+        .join(file)
+        .canonicalize()
+        .with_context(|| format!("Failed to read included file: {}", file))?;
+    if !path.starts_with(&root) {
+        bail!("Included file '{}' is outside the project", file);
+    }
+    Ok(path)
+}
+
+fn marker_name<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    let (_, rest) = line.split_once(marker)?;
+    rest.split_whitespace().next()
+}
+
+/// The lines between the start and end markers of a region, leaving out
+/// the markers of any other region inside it.
+fn region_lines<'a>(lines: &[&'a str], region: &str) -> Result<Vec<&'a str>> {
+    let is_start = |line: &str| {
+        marker_name(line, REGION_END).is_none() && marker_name(line, REGION_START) == Some(region)
+    };
+    let start = lines
+        .iter()
+        .position(|line| is_start(line))
+        .with_context(|| format!("Region '{}' not found", region))?;
+    let end = lines[start + 1..]
+        .iter()
+        .position(|line| marker_name(line, REGION_END) == Some(region))
+        .with_context(|| format!("Region '{}' is never closed", region))?;
+
+    Ok(lines[start + 1..start + 1 + end]
+        .iter()
+        .filter(|line| {
+            marker_name(line, REGION_END).is_none() && marker_name(line, REGION_START).is_none()
+        })
+        .copied()
+        .collect())
+}
+
+/// Joins the lines, removing the indentation they all share.
+fn dedent(lines: &[&str]) -> String {
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let mut snippet = String::new();
+    for line in lines {
+        snippet.push_str(line.get(indent..).unwrap_or("").trim_end());
+        snippet.push('\n');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src")
+    }
+
+    #[test]
+    fn resolve_include_stays_in_the_project() {
+        assert!(resolve_include(&source_dir(), "snippet.rs").is_ok());
+        assert!(resolve_include(&source_dir(), "cli/../snippet.rs").is_ok());
+        assert!(resolve_include(&source_dir(), "../Cargo.toml").is_err());
+        assert!(resolve_include(&source_dir(), "/etc/passwd").is_err());
+        assert!(resolve_include(&source_dir(), "missing.rs").is_err());
+    }
+
+    #[test]
+    fn region_lines_skips_nested_markers() {
+        let lines = [
+            "fn main() {",
+            "    // region: body",
+            "    // region: inner",
+            "    run();",
+            "    // endregion: inner",
+            "    // endregion: body",
+            "}",
+        ];
+        assert_eq!(region_lines(&lines, "body").unwrap(), vec!["    run();"]);
+        assert_eq!(region_lines(&lines, "inner").unwrap(), vec!["    run();"]);
+        assert!(region_lines(&lines, "missing").is_err());
+        assert!(region_lines(&lines[..3], "body").is_err());
+    }
+
+    #[test]
+    fn dedent_removes_shared_indentation() {
+        assert_eq!(dedent(&["    a", "", "      b  "]), "a\n\n  b\n");
+    }
+}
@@ -1,12 +1,11 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
-use anyhow::{Context, Result, anyhow, bail};
-use gray_matter::{Matter, engine::YAML};
+use anyhow::{Context, Result};
 use rust_embed::RustEmbed;
-use tokio::fs::{copy, create_dir_all, read_to_string};
-use walkdir::WalkDir;
+use slug::slugify;
+use tokio::fs::{copy, create_dir_all, metadata, read, read_to_string, write};
 
-use crate::consts::{Config, FrontMatter, GRIMOIRE_CONFIG_NAME};
+use crate::consts::{BUNDLE_INDEX, Config, FrontMatter, GRIMOIRE_CONFIG_NAME};
 
 #[derive(RustEmbed)]
 #[folder = "static"]
@@ -16,8 +15,7 @@ pub fn get_embedded_files(path: &str) -> Result<String> {
     let file =
         StaticAssets::get(path).context(format!("Failed to get the embedded file: {}", path))?;
 
-    Ok(String::from_utf8(file.data.to_vec())
-        .context("Failed to convert embedded file to string")?)
+    String::from_utf8(file.data.to_vec()).context("Failed to convert embedded file to string")
 }
 
 pub async fn get_content_dir() -> Result<String> {
@@ -38,69 +36,192 @@ pub async fn get_config() -> Result<Config> {
     Ok(config)
 }
 
-pub async fn get_slug<P: AsRef<Path>>(path: P) -> Result<String> {
-    let path_ref = path.as_ref();
-    let content = read_to_string(path_ref)
-        .await
-        .with_context(|| format!("Failed to read file: {}", path_ref.display()))?;
+/// Uses the slug from the front matter when there is one, otherwise derives
+/// it from the title and finally from the file name.
+pub fn resolve_slug(metadata: Option<&FrontMatter>, path: &Path) -> String {
+    if let Some(slug) = metadata
+        .and_then(|metadata| metadata.slug.as_deref())
+        .map(str::trim)
+        .filter(|slug| !slug.is_empty())
+    {
+        return slug.to_string();
+    }
 
-    let matter = Matter::<YAML>::new();
-    let res = matter.parse::<FrontMatter>(&content);
+    let from_title = metadata
+        .map(|metadata| slugify(&metadata.title))
+        .unwrap_or_default();
+    if !from_title.is_empty() {
+        return from_title;
+    }
+
+    // The index.md of a page bundle is named after its directory
+    let name_path = if path.file_name().is_some_and(|name| name == BUNDLE_INDEX) {
+        path.parent().unwrap_or(path)
+    } else {
+        path
+    };
+
+    name_path
+        .file_stem()
+        .map(|stem| slugify(stem.to_string_lossy()))
+        .unwrap_or_default()
+}
 
-    let data = res?
-        .data
-        .ok_or_else(|| anyhow!("No frontmatter found in file: {}", path_ref.display()))?;
+/// Returns the directory of a page bundle, i.e. an `index.md` that sits in a
+/// directory of its own below the section directory, next to the files it
+/// uses.
+pub fn get_bundle_dir<'a>(path: &'a Path, section_dir: &Path) -> Option<&'a Path> {
+    let parent = path.parent()?;
+    (path.file_name()? == BUNDLE_INDEX && parent != section_dir).then_some(parent)
+}
 
-    Ok(data.slug)
+/// Resolves the `.` and `..` components of a path without touching the file
+/// system, so paths can be compared and checked before their files exist.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // There is nothing above the root
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
 }
 
-pub async fn copy_dir<A: AsRef<Path>, B: AsRef<Path>>(from: A, to: B) -> Result<()> {
+/// Copies a file unless the destination already has the same size and is at
+/// least as new as the source. Returns whether the file was copied.
+pub async fn copy_if_changed<A: AsRef<Path>, B: AsRef<Path>>(from: A, to: B) -> Result<bool> {
     let from = from.as_ref();
     let to = to.as_ref();
 
-    if !from.exists() {
-        bail!(
-            "Source path {} does not exist",
-            from.to_str().unwrap_or("<invalid path>")
-        );
+    let source = metadata(from)
+        .await
+        .with_context(|| format!("Failed to read metadata of: {}", from.display()))?;
+
+    if let Ok(dest) = metadata(to).await
+        && dest.len() == source.len()
+        && dest.modified()? >= source.modified()?
+    {
+        return Ok(false);
     }
 
-    if !from.is_dir() {
-        bail!(
-            "Source path {} is not a directory",
-            from.to_str().unwrap_or("<invalid_path>")
-        );
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create parent directory: {}", parent.display()))?;
     }
 
-    create_dir_all(to)
+    copy(from, to).await.with_context(|| {
+        format!(
+            "Failed to copy file from {} to {}",
+            from.display(),
+            to.display()
+        )
+    })?;
+
+    Ok(true)
+}
+
+/// Writes `content` unless the file already holds exactly that, so that
+/// rebuilding doesn't touch assets that haven't changed.
+pub async fn write_if_changed<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, content: C) -> Result<bool> {
+    let path = path.as_ref();
+    let content = content.as_ref();
+
+    if read(path).await.is_ok_and(|existing| existing == content) {
+        return Ok(false);
+    }
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create parent directory: {}", parent.display()))?;
+    }
+    write(path, content)
         .await
-        .context(format!("Failed to create directory: {:?}", to))?;
-
-    for entry in WalkDir::new(from).into_iter().filter_map(|e| e.ok()) {
-        let entry_path = entry.path();
-
-        let rel_path = entry_path.strip_prefix(from)?;
-        let dest_path = to.join(rel_path);
-
-        if entry.file_type().is_dir() {
-            if !dest_path.exists() {
-                create_dir_all(&dest_path)
-                    .await
-                    .context(format!("Failed to create directory: {:?}", dest_path))?;
-            }
-        } else {
-            if let Some(parent) = dest_path.parent() {
-                create_dir_all(parent)
-                    .await
-                    .context(format!("Failed to create parent directory: {:?}", parent))?;
-            }
-
-            let _bytes_copied = copy(entry_path, &dest_path).await.context(format!(
-                "Failed to copy file from {:?} to {:?}",
-                entry_path, dest_path
-            ))?;
+        .with_context(|| format!("Failed to write file: {}", path.display()))?;
+    Ok(true)
+}
+
+/// Escapes a value for use inside a double quoted html attribute. Unlike
+/// `tera::escape_html` it leaves `/` alone, so urls stay readable.
+pub fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_resolves_dots() {
+        assert_eq!(
+            normalize_path(Path::new("content/blog/../static/./about.md")),
+            Path::new("content/static/about.md")
+        );
+        assert_eq!(normalize_path(Path::new("a/../../b")), Path::new("../b"));
+        assert_eq!(normalize_path(Path::new("/../etc")), Path::new("/etc"));
+    }
+
+    fn front_matter(title: &str, slug: Option<&str>) -> FrontMatter {
+        FrontMatter {
+            title: title.to_string(),
+            slug: slug.map(String::from),
+            ..Default::default()
         }
     }
 
-    Ok(())
+    #[test]
+    fn resolve_slug_prefers_the_front_matter_slug() {
+        let path = Path::new("content/blog/post.md");
+        assert_eq!(
+            resolve_slug(Some(&front_matter("Title", Some(" custom-slug "))), path),
+            "custom-slug"
+        );
+        assert_eq!(
+            resolve_slug(Some(&front_matter("My Title", Some("  "))), path),
+            "my-title"
+        );
+        assert_eq!(resolve_slug(None, path), "post");
+        assert_eq!(
+            resolve_slug(None, Path::new("content/blog/My Bundle/index.md")),
+            "my-bundle"
+        );
+    }
+
+    #[test]
+    fn resolve_slug_transliterates_unicode_titles() {
+        let path = Path::new("content/blog/post.md");
+        assert_eq!(
+            resolve_slug(Some(&front_matter("Café Über Straße", None)), path),
+            "cafe-uber-strasse"
+        );
+        assert_eq!(
+            resolve_slug(Some(&front_matter("日本", None)), path),
+            "ri-ben"
+        );
+        // Nothing left to slugify falls back to the file name
+        assert_eq!(resolve_slug(Some(&front_matter("!!!", None)), path), "post");
+    }
+
+    #[test]
+    fn resolve_slug_gives_similar_titles_the_same_slug() {
+        let path = Path::new("content/blog/post.md");
+        assert_eq!(
+            resolve_slug(Some(&front_matter("Hello, World!", None)), path),
+            resolve_slug(Some(&front_matter("hello world", None)), path)
+        );
+    }
 }
@@ -1 +1,15 @@
 console.log("hello");
+
+// Copy buttons for code blocks rendered with data-copy
+document.querySelectorAll("pre[data-copy]").forEach((pre) => {
+	const button = document.createElement("button");
+	button.type = "button";
+	button.className = "code-copy";
+	button.textContent = "Copy";
+	button.addEventListener("click", async () => {
+		await navigator.clipboard.writeText(pre.querySelector("code").textContent);
+		button.textContent = "Copied";
+		setTimeout(() => (button.textContent = "Copy"), 2000);
+	});
+	pre.appendChild(button);
+});